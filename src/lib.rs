@@ -1,388 +1,5940 @@
-pub struct Simple {
-    regfile: [u16; Self::REGISTER_COUNT],
-    ram: [u8; 65536],
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+
+macro_rules! trace {
+    ($simple:expr, $($arg:tt)*) => {
+        if $simple.trace {
+            eprintln!($($arg)*);
+        }
+    };
 }
 
-impl Simple {
-    const STACK_POINTER: usize = 15;
-    const INSTRUCTION_POINTER: usize = 16;
-    const FLAG_REGISTER: usize = 17;
-    const REGISTER_COUNT: usize = 16 * 4;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmuError {
+    UnalignedAccess { addr: usize },
+    ReadFault { addr: usize },
+    WriteFault { addr: usize },
+    ExecuteFault { addr: usize },
+    IllegalInstruction { addr: usize, word: u16 },
+    QuotaExceeded,
+    UninitializedRegister { reg: usize },
+    /// Execution ran off the end of the address space from the
+    /// instruction at `addr`.
+    InstructionPointerWrap { addr: usize },
+    InvariantViolation(Invariant),
+    MisalignedFetch { ip: u16 },
+    /// An ALU instruction at `addr` named an undefined operation.
+    IllegalAluOp { addr: usize, op: usize },
+    /// An access touched `addr`, which is outside every mapped range.
+    BusError { addr: usize },
+    /// The watchdog wasn't kicked in time.
+    WatchdogExpired,
+    /// A loader or debugger write to `addr`, which is past the end of RAM.
+    OutOfRange { addr: usize },
+}
 
-    pub fn new() -> Self {
-        Simple {
-            regfile: [0; Self::REGISTER_COUNT],
-            ram: [0; 65536],
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmuError::UnalignedAccess { addr } =>
+                write!(f, "unaligned word access at {:#06x}", addr),
+            EmuError::ReadFault { addr } =>
+                write!(f, "read from protected address {:#06x}", addr),
+            EmuError::WriteFault { addr } =>
+                write!(f, "write to protected address {:#06x}", addr),
+            EmuError::ExecuteFault { addr } =>
+                write!(f, "execute from protected address {:#06x}", addr),
+            EmuError::IllegalInstruction { addr, word } =>
+                write!(f, "illegal instruction {:#06x} at {:#06x}", word, addr),
+            EmuError::QuotaExceeded =>
+                write!(f, "memory access quota exceeded"),
+            EmuError::UninitializedRegister { reg } =>
+                write!(f, "read of uninitialized register r{}", reg),
+            EmuError::InstructionPointerWrap { addr } =>
+                write!(f, "instruction pointer wrapped after {:#06x}", addr),
+            EmuError::InvariantViolation(invariant) =>
+                write!(f, "invariant violated: {}", invariant),
+            EmuError::MisalignedFetch { ip } =>
+                write!(f, "instruction fetch from odd address {:#06x}", ip),
+            EmuError::IllegalAluOp { addr, op } =>
+                write!(f, "undefined alu operation {} at {:#06x}", op, addr),
+            EmuError::BusError { addr } =>
+                write!(f, "access to unmapped address {:#06x}", addr),
+            EmuError::WatchdogExpired =>
+                write!(f, "watchdog expired"),
+            EmuError::OutOfRange { addr } =>
+                write!(f, "address {:#06x} is past the end of memory", addr),
         }
     }
+}
 
-    pub fn load_program(&mut self, program: Vec<u8>) {
-        for (index, byte) in program.iter().enumerate() {
-            self.ram[index] = *byte;
+impl std::error::Error for EmuError {}
+
+/// A machine invariant found broken by sanity checking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invariant {
+    StackPointerOutOfRange { sp: u16 },
+    UndecodableInstruction { addr: usize, word: u16 },
+}
+
+impl fmt::Display for Invariant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Invariant::StackPointerOutOfRange { sp } =>
+                write!(f, "stack pointer {:#06x} is outside the stack region", sp),
+            Invariant::UndecodableInstruction { addr, word } =>
+                write!(f, "ip points at undecodable word {:#06x} at {:#06x}", word, addr),
         }
     }
+}
 
-    fn ip(&self) -> usize {
-        self.regfile[Self::INSTRUCTION_POINTER] as usize
+/// A relative jump or call at `addr` whose target falls outside the
+/// checked range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RangeError {
+    pub addr: usize,
+    pub target: usize,
+}
+
+/// A problem found by `Simple::verify_program`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// The word at `addr` doesn't decode to an instruction.
+    UndefinedOpcode { addr: usize, word: u16 },
+    /// A relative branch whose target is outside the program.
+    OutOfRange(RangeError),
+    /// A branch at `addr` to `target`, which is partway through another
+    /// instruction.
+    MidInstruction { addr: usize, target: usize },
+    /// The program ends partway through the instruction at `addr`.
+    Truncated { addr: usize },
+    /// Nothing in the program halts, so it runs off the end.
+    MissingHalt,
+}
+
+pub const PERM_R: u8 = 0b001;
+pub const PERM_W: u8 = 0b010;
+pub const PERM_X: u8 = 0b100;
+
+/// A protected range of memory. Accesses inside `range` need the matching
+/// `PERM_*` bit in `perms`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub range: Range<usize>,
+    pub perms: u8,
+}
+
+pub type BreakCondition = Box<dyn Fn(&Simple) -> bool>;
+
+/// A data read or write reported to the memory hook. `addr` is the
+/// physical address and `width` the size in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemAccess {
+    pub addr: usize,
+    pub is_write: bool,
+    pub value: u16,
+    pub width: u8,
+}
+
+pub type MemoryHook = Box<dyn FnMut(MemAccess)>;
+
+/// Given the address of each instruction word fetched, returns extra cycles
+/// to charge for it; see `Simple::set_fetch_hook`.
+pub type FetchHook = Box<dyn FnMut(u16) -> u64>;
+
+/// Emulates an illegal instruction word in software; see
+/// `Simple::set_illegal_handler`.
+pub type IllegalHandler = Box<dyn FnMut(&mut Simple, u16) -> bool>;
+
+/// Called with `(reg, old, new)` for each register a step changes; see
+/// `Simple::set_register_write_hook`.
+pub type RegisterHook = Box<dyn FnMut(usize, u16, u16)>;
+
+/// Called when the machine halts; see `Simple::set_halt_callback`.
+pub type HaltCallback = Box<dyn FnMut(&Simple)>;
+
+/// Stops `run` when IP reaches `addr`, once `ignore_count` earlier hits have
+/// been skipped. Visits where `condition` is false don't count as hits.
+pub struct Breakpoint {
+    pub addr: u16,
+    pub ignore_count: usize,
+    pub condition: Option<BreakCondition>,
+}
+
+impl Breakpoint {
+    pub fn new(addr: u16) -> Self {
+        Breakpoint {
+            addr,
+            ignore_count: 0,
+            condition: None,
+        }
     }
+}
 
-    fn advance_ip(&mut self, amount: usize) {
-        self.regfile[Self::INSTRUCTION_POINTER] = 
-            self.regfile[Self::INSTRUCTION_POINTER].wrapping_add(amount as u16);
+/// Backing store for the address space. `Vec<u8>` is the default; other
+/// implementations can be sparse, memory-mapped or instrumented.
+pub trait Memory {
+    fn read(&self, addr: usize) -> u8;
+    fn write(&mut self, addr: usize, value: u8);
+    /// Size of the address space in bytes.
+    fn size(&self) -> usize;
+
+    /// The whole address space as one slice, for backends that have one.
+    fn as_slice(&self) -> Option<&[u8]> {
+        None
     }
 
-    fn flags(&self) -> u16 {
-        self.regfile[Self::FLAG_REGISTER]
+    fn as_mut_slice(&mut self) -> Option<&mut [u8]> {
+        None
     }
+}
 
-    fn zf(&self) -> bool {
-        self.flags() & alu::ZF > 0
+impl Memory for Vec<u8> {
+    fn read(&self, addr: usize) -> u8 {
+        self[addr]
     }
 
-    fn cf(&self) -> bool {
-        self.flags() & alu::CF > 0
+    fn write(&mut self, addr: usize, value: u8) {
+        self[addr] = value;
     }
 
-    fn of(&self) -> bool {
-        self.flags() & alu::OF > 0
+    fn size(&self) -> usize {
+        self.len()
     }
 
-    fn sf(&self) -> bool {
-        self.flags() & alu::SF > 0
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(self)
     }
 
-    fn ef(&self) -> bool {
-        self.flags() & alu::EF > 0
+    fn as_mut_slice(&mut self) -> Option<&mut [u8]> {
+        Some(self)
     }
+}
+
+/// A 64KB address space backed by a reader, paged in as addresses are
+/// touched so that large program images aren't read up front. Addresses
+/// past the end of the stream read as zero.
+pub struct StreamingMemory<R> {
+    stream: RefCell<Stream<R>>,
+}
+
+struct Stream<R> {
+    reader: R,
+    data: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> StreamingMemory<R> {
+    pub const PAGE_SIZE: usize = 256;
 
-    fn read_16(&self, address: usize) -> u16 {
-        ((self.ram[address] as u16) << 8) +
-            self.ram[address.wrapping_add(1)] as u16
+    pub fn new(reader: R) -> Self {
+        StreamingMemory {
+            stream: RefCell::new(Stream { reader, data: Vec::new(), done: false }),
+        }
     }
 
-    fn write_16(&mut self, address: usize, value: u16) {
-        match address {
-            0xFF01 => {
-                eprintln!("OUT: {}", value);
-            }
-            _ => {
-                self.ram[address] = (value >> 8) as u8;
-                self.ram[address.wrapping_add(1)] = value as u8;
+    fn page_in(&self, addr: usize) {
+        let mut stream = self.stream.borrow_mut();
+        let Stream { reader, data, done } = &mut *stream;
+        while !*done && data.len() <= addr {
+            // A read error ends the stream like EOF; memory can't fail.
+            let page = Self::PAGE_SIZE as u64;
+            match reader.by_ref().take(page).read_to_end(data) {
+                Ok(n) if n as u64 == page => {}
+                _ => *done = true,
             }
         }
     }
+}
 
-    fn push(&mut self, value: u16) {
-        self.regfile[Self::STACK_POINTER] =
-            self.regfile[Self::STACK_POINTER].wrapping_sub(2);
-        self.write_16(self.regfile[Self::STACK_POINTER] as usize, value);
+impl<R: Read> Memory for StreamingMemory<R> {
+    fn read(&self, addr: usize) -> u8 {
+        self.page_in(addr);
+        self.stream.borrow().data.get(addr).copied().unwrap_or(0)
     }
 
-    fn pop(&mut self) -> u16 {
-        let value = self.read_16(self.regfile[Self::STACK_POINTER] as usize);
-        self.regfile[Self::STACK_POINTER] =
-            self.regfile[Self::STACK_POINTER].wrapping_add(2);
-        value
+    fn write(&mut self, addr: usize, value: u8) {
+        self.page_in(addr);
+        let data = &mut self.stream.get_mut().data;
+        if data.len() <= addr {
+            data.resize(addr + 1, 0);
+        }
+        data[addr] = value;
     }
 
-    fn should_jump(&self, cond: usize) -> bool {
-        match cond {
-            1 => !self.zf() && !self.cf(),
-            2 => !self.cf(),
-            3 => self.cf(),
-            4 => self.cf() || self.zf(),
-            5 => !self.zf() && self.sf() == self.of(),
-            6 => self.sf() == self.of(),
-            7 => self.sf() != self.of(),
-            8 => !self.zf() || self.sf() != self.zf(),
-            9 => self.zf(),
-            10 => !self.zf(),
-            11 => self.of(),
-            12 => !self.of(),
-            13 => true,
-            _ => false,
-        }
-    }
-
-    pub fn step(&mut self) -> bool {
-        let instruction = self.read_16(self.ip()) as usize;
-        eprintln!("{:>2}: {:0>16b}  {:>4x?}",
-            self.ip(), instruction, &self.regfile[0..16]);
-        if instruction == 0 {
-            return false;
-        }
-        match instruction >> 12 {
-            0b0000 if instruction >> 8 == 0 => { // 1op
+    fn size(&self) -> usize {
+        65536
+    }
+}
+
+/// Label names and the addresses they stand for, so debugging tools can
+/// refer to code by name.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SymbolTable {
+    symbols: HashMap<String, u16>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        self.symbols.insert(name.to_string(), addr);
+    }
+
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).copied()
+    }
+
+    /// The labels at `addr`, sorted.
+    pub fn names_at(&self, addr: u16) -> Vec<&str> {
+        let mut names: Vec<&str> = self.symbols.iter()
+            .filter(|&(_, &a)| a == addr)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+/// A snapshot of the general-purpose and special registers, from
+/// `Simple::registers`. `sp` is `r15` under its usual name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub r0: u16,
+    pub r1: u16,
+    pub r2: u16,
+    pub r3: u16,
+    pub r4: u16,
+    pub r5: u16,
+    pub r6: u16,
+    pub r7: u16,
+    pub r8: u16,
+    pub r9: u16,
+    pub r10: u16,
+    pub r11: u16,
+    pub r12: u16,
+    pub r13: u16,
+    pub r14: u16,
+    pub r15: u16,
+    pub sp: u16,
+    pub ip: u16,
+    pub flags: alu::Flags,
+}
+
+/// The differences between two machines, from `Simple::diff_state`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct StateDiff {
+    /// `(register, ours, theirs)` for each register that differs.
+    pub registers: Vec<(usize, u16, u16)>,
+    /// Runs of differing memory bytes.
+    pub memory: Vec<Range<usize>>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.memory.is_empty()
+    }
+}
+
+/// How words written to the console port `0xFF01` are shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConsoleMode {
+    /// Each write prints its value as a number on its own line.
+    #[default]
+    Numeric,
+    /// The low byte of each write is an ASCII character; output is line
+    /// buffered.
+    Ascii,
+}
+
+/// What the watchdog does when it expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogAction {
+    /// `Simple::reset` the machine and carry on.
+    Reset,
+    /// Fail the step with `EmuError::WatchdogExpired`.
+    Fault,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Watchdog {
+    kick: usize,
+    timeout: u64,
+    action: WatchdogAction,
+    last_kick: u64,
+}
+
+/// Why `run` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Halted,
+    Breakpoint,
+    /// A step left `reg` holding `value`, as set by
+    /// `Simple::add_register_breakpoint`.
+    RegisterBreak { reg: usize, value: u16 },
+    QuotaExceeded,
+    StepLimit,
+    InstructionPointerWrap,
+    Fault(EmuError),
+}
+
+/// Counts from `Simple::run_stats`. Every jump, call and `ret` counts as
+/// a branch, taken or not; calls and returns are also counted when taken.
+/// Memory counts are data accesses, as seen by the memory hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RunStats {
+    pub branches_taken: u64,
+    pub branches_not_taken: u64,
+    pub calls: u64,
+    pub returns: u64,
+    pub memory_reads: u64,
+    pub memory_writes: u64,
+    /// Why the run ended.
+    pub stop_reason: Option<StopReason>,
+}
+
+/// A programmable interrupt controller with 16 lines, where lower-numbered
+/// lines have priority. Guests program it through the `IRQ_*_PORT`
+/// addresses; hosts raise lines with `Simple::raise_irq`.
+///
+/// A delivered line stays in service, blocking itself and lower-priority
+/// lines, until the guest writes its number to `IRQ_ACK_PORT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptController {
+    /// Set bits are masked. All lines start out masked.
+    pub mask: u16,
+    pub pending: u16,
+    pub in_service: u16,
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        InterruptController { mask: 0xFFFF, pending: 0, in_service: 0 }
+    }
+}
+
+impl InterruptController {
+    pub const LINES: u8 = 16;
+
+    pub fn raise(&mut self, line: u8) {
+        assert!(line < Self::LINES, "no interrupt line {}", line);
+        self.pending |= 1 << line;
+    }
+
+    pub fn acknowledge(&mut self, line: u8) {
+        if line < Self::LINES {
+            self.in_service &= !(1 << line);
+        }
+    }
+
+    /// The line that would be delivered now, if any.
+    pub fn next(&self) -> Option<u8> {
+        let ready = self.pending & !self.mask;
+        if ready == 0 {
+            return None;
+        }
+        let line = ready.trailing_zeros();
+        let outranking = ((2u32 << line) - 1) as u16;
+        if self.in_service & outranking != 0 {
+            return None;
+        }
+        Some(line as u8)
+    }
+
+    fn deliver(&mut self, line: u8) {
+        self.pending &= !(1 << line);
+        self.in_service |= 1 << line;
+    }
+}
+
+/// Progress made by one call to `emulate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Emulated {
+    pub cycles_used: u64,
+    /// `StopReason::StepLimit` if the budget ran out.
+    pub stop_reason: StopReason,
+}
+
+/// A control-flow instruction as executed, from `Simple::last_was_branch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BranchInfo {
+    pub addr: u16,
+    /// Where IP went next; the fall-through if not taken.
+    pub target: u16,
+    pub taken: bool,
+}
+
+/// One step as seen by `Simple::step_detailed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepInfo {
+    pub addr: u16,
+    pub instruction: Instruction,
+    /// The registers the step changed, with their new values, in register
+    /// order.
+    pub changed: Vec<(usize, u16)>,
+    /// Whether the step halted the machine.
+    pub halted: bool,
+}
+
+/// A basic block run by `Simple::step_block`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockInfo {
+    /// The address and decoding of each instruction run, in order.
+    pub instructions: Vec<(u16, Instruction)>,
+    /// The address of the last instruction and the IP it left behind.
+    pub exit: (u16, u16),
+}
+
+/// What `step` does when it fetches the zero word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HaltPolicy {
+    #[default]
+    HaltOnZero,
+    TreatZeroAsNop,
+    /// Zero is an illegal instruction; only `hlt` stops the machine.
+    RequireExplicitHalt,
+}
+
+/// How a narrow immediate is widened to 16 bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImmediateMode {
+    #[default]
+    ZeroExtend,
+    SignExtend,
+}
+
+/// Where an absolute jump or call goes, selected by the low nibble (`typ`)
+/// of the instruction:
+///
+/// | typ  | target                                             |
+/// |------|----------------------------------------------------|
+/// | 0    | `rd`                                               |
+/// | 1    | the word at `[rd]`                                 |
+/// | 2    | the following word                                 |
+/// | 3    | the end of the instruction plus the following word |
+/// | 4    | the word at the address stored at `[rd]`           |
+/// | 5-15 | illegal                                            |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JumpTarget {
+    Register(usize),
+    Indirect(usize),
+    Immediate(u16),
+    Relative(i16),
+    DoubleIndirect(usize),
+}
+
+/// A decoded instruction. Data moves (the `mov` forms, loads, stores,
+/// pushes and pops) never touch the flags; only ALU operations, `cmpi`,
+/// `cas` and `sahf` set them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// The zero word, whose meaning depends on the `HaltPolicy`.
+    Zero,
+    Not { rd: usize },
+    Neg { rd: usize },
+    Push { rd: usize },
+    Pop { rd: usize },
+    Inc { rd: usize },
+    Dec { rd: usize },
+    /// Signed absolute value; sets OF for `0x8000`.
+    Abs { rd: usize },
+    /// Two's complement negation with flags, unlike `Neg`.
+    SNeg { rd: usize },
+    /// Copy the flags into `rd`.
+    LoadFlags { rd: usize },
+    /// Set the flags from `rd`, ignoring bits that aren't flags.
+    StoreFlags { rd: usize },
+    Alu { op: usize, rd: usize, rs: usize },
+    Jump { cond: usize, target: JumpTarget },
+    /// `n` is the raw 4-bit immediate; it's widened at execution time
+    /// according to the machine's `ImmediateMode`.
+    AluImmediate { op: usize, rd: usize, n: u16 },
+    /// `offset` is in bytes, relative to the end of this instruction.
+    JumpRelative { cond: usize, offset: i8 },
+    Load { rd: usize, rs: usize, ro: usize },
+    Store { rd: usize, rs: usize, ro: usize },
+    /// Load from `[rs + disp]`. `disp` is in bytes, encoded as a signed
+    /// 4-bit count of words, so it covers -16 to 14.
+    LoadDisplaced { rd: usize, rs: usize, disp: i8 },
+    Call { cond: usize, target: JumpTarget },
+    CallRelative { cond: usize, offset: i8 },
+    MovI8 { rd: usize, n: u8 },
+    MovI16 { rd: usize, n: u16 },
+    /// Compare the word at `[ra]` with `re` and store `rn` there if they
+    /// match. Flags are set as for `cmp`, so ZF means the swap happened.
+    Cas { ra: usize, re: usize, rn: usize },
+    /// Branch if `rd` is zero (or nonzero) without touching the flags.
+    /// `offset` is a 7-bit displacement relative to the end of the
+    /// instruction.
+    JumpRegister { rd: usize, nonzero: bool, offset: i8 },
+    /// Q8.8 fixed-point multiply: `rd = (rd * rs) >> 8`, with the full
+    /// 32-bit product left in the accumulator.
+    FixedMul { rd: usize, rs: usize, signed: bool },
+    /// Load the low 16 bits of the cycle counter into `rd`.
+    ReadCycles { rd: usize },
+    /// Compare `ra` with `rb` and branch on `cond` as a `cmp` and jump
+    /// would, but without touching the flags. `offset` is a 12-bit
+    /// displacement relative to the end of the instruction.
+    CompareJump { cond: usize, ra: usize, rb: usize, offset: i16 },
+    /// Push each register r0-r15 whose bit is set in `mask`, lowest
+    /// first.
+    PushMultiple { mask: u16 },
+    /// Pop into each register whose bit is set in `mask`, highest first,
+    /// undoing a `PushMultiple` with the same mask.
+    PopMultiple { mask: u16 },
+    /// Does nothing but advance IP, whatever the `HaltPolicy`.
+    Nop,
+    /// Switch compressed mode on or off; see `decode_compressed`. Turning
+    /// it off while it's already off does nothing.
+    Compressed(bool),
+    Halt,
+    Ret,
+    Mov { rd: usize, rs: usize },
+    /// Load a 32-bit immediate, taken from the two following words (high
+    /// word first), into the accumulator pair.
+    LoadAcc32 { n: u32 },
+    /// Store the low byte of `rs` at `[rd]`.
+    StoreByte { rd: usize, rs: usize },
+    /// Set flags as `cmp` would for `rd` and the 16-bit immediate `n`.
+    CompareImmediate { rd: usize, n: u16 },
+    /// Halt with `rd` as the exit code.
+    HaltWithCode { rd: usize },
+    Illegal(u16),
+}
+
+impl Instruction {
+    /// Decode `word`, taking an immediate from `next` if the encoding has
+    /// one. 32-bit immediates need `decode_wide`; here their low word is 0.
+    pub fn decode(word: u16, next: u16) -> Self {
+        Self::decode_wide(word, next, 0)
+    }
+
+    /// Like `decode`, with `next2` as the word after `next` for encodings
+    /// with a 32-bit immediate.
+    pub fn decode_wide(word: u16, next: u16, next2: u16) -> Self {
+        use Instruction::*;
+        let instruction = word as usize;
+        match word >> opcodes::CLASS_SHIFT {
+            opcodes::ALU if word == 0 => Zero,
+            opcodes::ALU if word >> 8 == 0 => {
                 let rd = instruction & 0b1111;
-                match instruction >> 4 {
-                    1 => self.regfile[rd] = !self.regfile[rd],
-                    2 => self.regfile[rd] = !self.regfile[rd].wrapping_add(1),
-                    3 => self.push(self.regfile[rd]),
-                    4 => self.regfile[rd] = self.pop(),
-                    5 => self.regfile[rd] = self.regfile[rd].wrapping_add(1),
-                    6 => self.regfile[rd] = self.regfile[rd].wrapping_sub(1),
-                    _ => todo!(),
-                };
-                self.advance_ip(2);
-                true
-            }
-            0b0000 => { // 2op
-                let op = (instruction >> 8) & 0b1111;
-                let rd = (instruction >> 4) & 0b1111;
-                let rs = instruction & 0b1111;
-                let va = self.regfile[rd];
-                let vb = self.regfile[rs];
-                let (result, flags) = alu::alu(op, va, vb, self.flags());
-                self.regfile[Self::FLAG_REGISTER] = flags;
-                self.regfile[rd] = result;
-                self.advance_ip(2);
-                true
+                match word >> 4 {
+                    opcodes::NOT => Not { rd },
+                    opcodes::NEG => Neg { rd },
+                    opcodes::PUSH => Push { rd },
+                    opcodes::POP => Pop { rd },
+                    opcodes::INC => Inc { rd },
+                    opcodes::DEC => Dec { rd },
+                    opcodes::ABS => Abs { rd },
+                    opcodes::SNEG => SNeg { rd },
+                    opcodes::LAHF => LoadFlags { rd },
+                    opcodes::SAHF => StoreFlags { rd },
+                    _ => Illegal(word),
+                }
             }
-            0b0001 => { // j? abs
+            opcodes::ALU => Alu {
+                op: (instruction >> 8) & 0b1111,
+                rd: (instruction >> 4) & 0b1111,
+                rs: instruction & 0b1111,
+            },
+            opcodes::JUMP | opcodes::CALL => {
                 let cond = (instruction >> 8) & 0b1111;
                 let rd = (instruction >> 4) & 0b1111;
-                let typ = instruction & 0b1111;
-                let has_immediate = typ == 2;
-                let target;
-                match typ {
-                    0 => target = self.regfile[rd],
-                    1 => target = self.read_16(self.regfile[rd] as usize),
-                    2 => target = self.read_16(self.ip().wrapping_add(2)),
-                    _ => todo!(),
-                }
-                self.advance_ip(2);
-                if has_immediate {
-                    self.advance_ip(2);
-                }
-                if self.should_jump(cond) {
-                    eprintln!("jmp {}", target as i16);
-                    self.regfile[Self::INSTRUCTION_POINTER] = target;
+                let target = match word & 0b1111 {
+                    opcodes::TARGET_REGISTER => JumpTarget::Register(rd),
+                    opcodes::TARGET_INDIRECT => JumpTarget::Indirect(rd),
+                    opcodes::TARGET_IMMEDIATE => JumpTarget::Immediate(next),
+                    opcodes::TARGET_RELATIVE => JumpTarget::Relative(next as i16),
+                    opcodes::TARGET_DOUBLE_INDIRECT => JumpTarget::DoubleIndirect(rd),
+                    _ => return Illegal(word),
+                };
+                if word >> opcodes::CLASS_SHIFT == opcodes::JUMP {
+                    Jump { cond, target }
+                } else {
+                    Call { cond, target }
                 }
-                true
             }
-            0b0010 => { // 2op immediate
-                let op = (instruction >> 8) & 0b1111;
+            opcodes::ALU_IMMEDIATE => AluImmediate {
+                op: (instruction >> 8) & 0b1111,
+                rd: (instruction >> 4) & 0b1111,
+                n: (instruction & 0b1111) as u16,
+            },
+            opcodes::JUMP_RELATIVE => JumpRelative {
+                cond: (instruction >> 8) & 0b1111,
+                offset: instruction as i8,
+            },
+            opcodes::LOAD => Load {
+                rd: (instruction >> 8) & 0b1111,
+                rs: (instruction >> 4) & 0b1111,
+                ro: instruction & 0b1111,
+            },
+            opcodes::STORE => Store {
+                rd: (instruction >> 8) & 0b1111,
+                rs: (instruction >> 4) & 0b1111,
+                ro: instruction & 0b1111,
+            },
+            opcodes::CALL_RELATIVE => CallRelative {
+                cond: (instruction >> 8) & 0b1111,
+                offset: instruction as i8,
+            },
+            opcodes::MOV_I8 => MovI8 {
+                rd: (instruction >> 8) & 0b1111,
+                n: instruction as u8,
+            },
+            opcodes::MOV_I16 => MovI16 {
+                rd: (instruction >> 8) & 0b1111,
+                n: next,
+            },
+            opcodes::MISC => {
                 let rd = (instruction >> 4) & 0b1111;
-                let n = (instruction & 0b1111) as u16;
-                let va = self.regfile[rd];
-                let (result, flags) = alu::alu(op, va, n, self.flags());
-                self.regfile[Self::FLAG_REGISTER] = flags;
-                self.regfile[rd] = result;
-                self.advance_ip(2);
-                true
-            }
-            0b0011 => { // j? relative
-                let cond = (instruction >> 8) & 0b1111;
-                let target = (instruction & 0b1111_1111) as i8 as i16 as usize;
-                self.advance_ip(2);
-                if self.should_jump(cond) {
-                    eprintln!("jmp relative {}", target as i16);
-                    self.advance_ip(target);
+                let rs = instruction & 0b1111;
+                match (word >> 8) & 0b1111 {
+                    opcodes::MISC_CONTROL => match word & 0b1111_1111 {
+                        opcodes::HALT => Halt,
+                        opcodes::RET => Ret,
+                        opcodes::NOP => Nop,
+                        opcodes::CMODE_ON => Compressed(true),
+                        opcodes::CMODE_OFF => Compressed(false),
+                        _ => Illegal(word),
+                    },
+                    opcodes::MISC_FMUL => FixedMul { rd, rs, signed: true },
+                    opcodes::MISC_FMULU => FixedMul { rd, rs, signed: false },
+                    opcodes::MISC_RDCYC if rs == 0 => ReadCycles { rd },
+                    opcodes::MISC_LDACC if rd == 0 && rs == 0 =>
+                        LoadAcc32 { n: (next as u32) << 16 | next2 as u32 },
+                    opcodes::MISC_PUSHM if rd == 0 && rs == 0 => PushMultiple { mask: next },
+                    opcodes::MISC_POPM if rd == 0 && rs == 0 => PopMultiple { mask: next },
+                    opcodes::MISC_STB => StoreByte { rd, rs },
+                    opcodes::MISC_CMPI if rs == 0 => CompareImmediate { rd, n: next },
+                    opcodes::MISC_HLTR if rs == 0 => HaltWithCode { rd },
+                    opcodes::MISC_CMPJ => CompareJump {
+                        cond: (next >> 12) as usize,
+                        ra: rd,
+                        rb: rs,
+                        offset: ((next << 4) as i16) >> 4,
+                    },
+                    _ => Illegal(word),
                 }
-                true
             }
-            0b0100 => { // mov rN, [rS + rO]
+            opcodes::MOV => {
                 let rd = (instruction >> 8) & 0b1111;
                 let rs = (instruction >> 4) & 0b1111;
-                let ro = instruction & 0b1111;
-                let address = self.regfile[rs].wrapping_add(self.regfile[ro]);
-                self.regfile[rd] = self.read_16(address as usize);
-                self.advance_ip(2);
-                true
+                let pd = (instruction >> 2) & 0b11;
+                let ps = instruction & 0b11;
+                Mov { rd: rd + pd * 16, rs: rs + ps * 16 }
             }
-            0b0101 => { // mov [rN + rO], rS
-                let rd = (instruction >> 8) & 0b1111;
-                let rs = (instruction >> 4) & 0b1111;
-                let ro = instruction & 0b1111;
-                let address = self.regfile[rd].wrapping_add(self.regfile[ro]);
-                self.write_16(address as usize, self.regfile[rs]);
-                self.advance_ip(2);
-                true
+            opcodes::CAS => Cas {
+                ra: (instruction >> 8) & 0b1111,
+                re: (instruction >> 4) & 0b1111,
+                rn: instruction & 0b1111,
+            },
+            opcodes::JUMP_REGISTER => JumpRegister {
+                rd: (instruction >> 8) & 0b1111,
+                nonzero: instruction & 0b1000_0000 != 0,
+                offset: ((instruction << 1) as i8) >> 1,
+            },
+            opcodes::LOAD_DISPLACED => LoadDisplaced {
+                rd: (instruction >> 8) & 0b1111,
+                rs: (instruction >> 4) & 0b1111,
+                disp: ((instruction << 4) as i8) >> 3,
+            },
+            _ => Illegal(word),
+        }
+    }
+
+    /// The words this instruction encodes to; `size` bytes in total.
+    pub fn encode(&self) -> Vec<u16> {
+        use Instruction::*;
+        use opcodes::*;
+        let class = |class: u16| class << CLASS_SHIFT;
+        let fields = |a: usize, b: usize, c: usize| (a << 8 | b << 4 | c) as u16;
+        let target_fields = |target: JumpTarget| match target {
+            JumpTarget::Register(rd) => (rd, TARGET_REGISTER, None),
+            JumpTarget::Indirect(rd) => (rd, TARGET_INDIRECT, None),
+            JumpTarget::Immediate(n) => (0, TARGET_IMMEDIATE, Some(n)),
+            JumpTarget::Relative(n) => (0, TARGET_RELATIVE, Some(n as u16)),
+            JumpTarget::DoubleIndirect(rd) => (rd, TARGET_DOUBLE_INDIRECT, None),
+        };
+        let branch = |opcode: u16, cond: usize, target: JumpTarget| {
+            let (rd, typ, next) = target_fields(target);
+            let word = class(opcode) | fields(cond, rd, 0) | typ;
+            match next {
+                Some(next) => vec![word, next],
+                None => vec![word],
             }
-            0b0110 => { // call? abs
-                // COPYPASTE from 0b0001
-                let cond = (instruction >> 8) & 0b1111;
-                let rd = (instruction >> 4) & 0b1111;
-                let typ = instruction & 0b1111;
-                let has_immediate = typ == 2;
-                let target;
-                match typ {
-                    0 => target = self.regfile[rd],
-                    1 => target = self.read_16(self.regfile[rd] as usize),
-                    2 => target = self.read_16(self.ip().wrapping_add(2)),
-                    _ => todo!(),
-                }
-                self.advance_ip(2);
-                if has_immediate {
-                    self.advance_ip(2);
-                }
-                if self.should_jump(cond) {
-                    eprintln!("call {}", target as i16);
-                    self.push(self.regfile[Self::INSTRUCTION_POINTER]);
-                    self.regfile[Self::INSTRUCTION_POINTER] = target;
+        };
+        let word = match *self {
+            Zero => 0,
+            Not { rd } => NOT << 4 | rd as u16,
+            Neg { rd } => NEG << 4 | rd as u16,
+            Push { rd } => PUSH << 4 | rd as u16,
+            Pop { rd } => POP << 4 | rd as u16,
+            Inc { rd } => INC << 4 | rd as u16,
+            Dec { rd } => DEC << 4 | rd as u16,
+            Abs { rd } => ABS << 4 | rd as u16,
+            SNeg { rd } => SNEG << 4 | rd as u16,
+            LoadFlags { rd } => LAHF << 4 | rd as u16,
+            StoreFlags { rd } => SAHF << 4 | rd as u16,
+            Alu { op, rd, rs } => class(ALU) | fields(op, rd, rs),
+            Jump { cond, target } => return branch(JUMP, cond, target),
+            Call { cond, target } => return branch(CALL, cond, target),
+            AluImmediate { op, rd, n } => class(ALU_IMMEDIATE) | fields(op, rd, n as usize),
+            JumpRelative { cond, offset } =>
+                class(JUMP_RELATIVE) | fields(cond, 0, 0) | offset as u8 as u16,
+            CallRelative { cond, offset } =>
+                class(CALL_RELATIVE) | fields(cond, 0, 0) | offset as u8 as u16,
+            Load { rd, rs, ro } => class(LOAD) | fields(rd, rs, ro),
+            Store { rd, rs, ro } => class(STORE) | fields(rd, rs, ro),
+            LoadDisplaced { rd, rs, disp } =>
+                class(LOAD_DISPLACED) | fields(rd, rs, (disp >> 1) as usize & 0b1111),
+            MovI8 { rd, n } => class(MOV_I8) | fields(rd, 0, 0) | n as u16,
+            MovI16 { rd, n } => return vec![class(MOV_I16) | fields(rd, 0, 0), n],
+            Cas { ra, re, rn } => class(CAS) | fields(ra, re, rn),
+            JumpRegister { rd, nonzero, offset } => class(JUMP_REGISTER) |
+                fields(rd, 0, 0) | (nonzero as u16) << 7 | (offset as u16 & 0b111_1111),
+            FixedMul { rd, rs, signed } => {
+                let op = if signed { MISC_FMUL } else { MISC_FMULU };
+                class(MISC) | fields(op as usize, rd, rs)
+            }
+            ReadCycles { rd } => class(MISC) | fields(MISC_RDCYC as usize, rd, 0),
+            StoreByte { rd, rs } => class(MISC) | fields(MISC_STB as usize, rd, rs),
+            CompareImmediate { rd, n } =>
+                return vec![class(MISC) | fields(MISC_CMPI as usize, rd, 0), n],
+            HaltWithCode { rd } => class(MISC) | fields(MISC_HLTR as usize, rd, 0),
+            CompareJump { cond, ra, rb, offset } => return vec![
+                class(MISC) | fields(MISC_CMPJ as usize, ra, rb),
+                (cond as u16) << 12 | (offset as u16 & 0xfff),
+            ],
+            LoadAcc32 { n } =>
+                return vec![class(MISC) | MISC_LDACC << 8, (n >> 16) as u16, n as u16],
+            PushMultiple { mask } => return vec![class(MISC) | MISC_PUSHM << 8, mask],
+            PopMultiple { mask } => return vec![class(MISC) | MISC_POPM << 8, mask],
+            Nop => class(MISC) | MISC_CONTROL << 8 | NOP,
+            Compressed(on) =>
+                class(MISC) | MISC_CONTROL << 8 | if on { CMODE_ON } else { CMODE_OFF },
+            Halt => class(MISC) | MISC_CONTROL << 8 | HALT,
+            Ret => class(MISC) | MISC_CONTROL << 8 | RET,
+            Mov { rd, rs } =>
+                class(MOV) | fields(rd % 16, rs % 16, (rd / 16) * 4 + rs / 16),
+            Illegal(word) => word,
+        };
+        vec![word]
+    }
+
+    /// `nop`s to pad a program from `addr` up to the next multiple of
+    /// `align` bytes, e.g. to line up a jump table. Panics if `addr` is
+    /// odd, since instructions are whole words.
+    pub fn alignment_padding(addr: usize, align: usize) -> Vec<u8> {
+        assert!(addr.is_multiple_of(2), "can't pad from odd address {:#x}", addr);
+        let nops = (align - addr % align) % align / 2;
+        Instruction::Nop.encode()[0].to_be_bytes().repeat(nops)
+    }
+
+    /// Encoded size in bytes.
+    pub fn size(&self) -> usize {
+        use Instruction::*;
+        match self {
+            Jump { target: JumpTarget::Immediate(_), .. } => 4,
+            Jump { target: JumpTarget::Relative(_), .. } => 4,
+            Call { target: JumpTarget::Immediate(_), .. } => 4,
+            Call { target: JumpTarget::Relative(_), .. } => 4,
+            MovI16 { .. } => 4,
+            CompareJump { .. } => 4,
+            PushMultiple { .. } | PopMultiple { .. } => 4,
+            CompareImmediate { .. } => 4,
+            LoadAcc32 { .. } => 6,
+            _ => 2,
+        }
+    }
+
+    /// Decode a one-byte compressed-mode instruction. Only `inc`, `dec`,
+    /// `push`, `pop`, `not`, `neg`, `mov rN, 0-7`, `hlt` and `cmode off`
+    /// have compressed forms.
+    pub fn decode_compressed(byte: u8) -> Self {
+        use Instruction::*;
+        let rd = (byte & 0b1111) as usize;
+        if byte & opcodes::COMPRESSED_MOV != 0 {
+            return MovI8 { rd, n: byte >> 4 & 0b111 };
+        }
+        match byte {
+            opcodes::COMPRESSED_OFF => return Compressed(false),
+            opcodes::COMPRESSED_HALT => return Halt,
+            _ => {}
+        }
+        match byte >> 4 {
+            opcodes::COMPRESSED_INC => Inc { rd },
+            opcodes::COMPRESSED_DEC => Dec { rd },
+            opcodes::COMPRESSED_PUSH => Push { rd },
+            opcodes::COMPRESSED_POP => Pop { rd },
+            opcodes::COMPRESSED_NOT => Not { rd },
+            opcodes::COMPRESSED_NEG => Neg { rd },
+            _ => Illegal(byte as u16),
+        }
+    }
+
+    /// The compressed-mode byte for this, if it has one.
+    pub fn encode_compressed(&self) -> Option<u8> {
+        use opcodes::*;
+        use Instruction::*;
+        let one = |op: u8, rd: usize| Some(op << 4 | rd as u8);
+        match *self {
+            Inc { rd } => one(COMPRESSED_INC, rd),
+            Dec { rd } => one(COMPRESSED_DEC, rd),
+            Push { rd } => one(COMPRESSED_PUSH, rd),
+            Pop { rd } => one(COMPRESSED_POP, rd),
+            Not { rd } => one(COMPRESSED_NOT, rd),
+            Neg { rd } => one(COMPRESSED_NEG, rd),
+            MovI8 { rd, n } if rd < 16 && n < 8 => Some(COMPRESSED_MOV | n << 4 | rd as u8),
+            Compressed(false) => Some(COMPRESSED_OFF),
+            Halt => Some(COMPRESSED_HALT),
+            _ => None,
+        }
+    }
+
+    /// The IP-relative displacement of a branch, in bytes from the end of
+    /// the instruction.
+    fn branch_offset(&self) -> Option<i16> {
+        use Instruction::*;
+        match *self {
+            JumpRelative { offset, .. } |
+            CallRelative { offset, .. } |
+            JumpRegister { offset, .. } => Some(offset.into()),
+            CompareJump { offset, .. } => Some(offset),
+            Jump { target: JumpTarget::Relative(offset), .. } |
+            Call { target: JumpTarget::Relative(offset), .. } => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// Where this branches to when it's at `addr`, for branches with a
+    /// relative or immediate target.
+    pub fn branch_target(&self, addr: u16) -> Option<u16> {
+        match *self {
+            Instruction::Jump { target: JumpTarget::Immediate(n), .. } |
+            Instruction::Call { target: JumpTarget::Immediate(n), .. } => Some(n),
+            _ => self.branch_offset().map(|offset| {
+                addr.wrapping_add(self.size() as u16).wrapping_add(offset as u16)
+            }),
+        }
+    }
+
+    /// Like `to_string`, but with the branch target resolved against
+    /// `addr` and labelled from `symbols`, as in `jmp 0x0008 <loop>`.
+    pub fn display_at(&self, addr: u16, symbols: &SymbolTable) -> String {
+        let text = self.to_string();
+        let target = match self.branch_target(addr) {
+            Some(target) => target,
+            None => return text,
+        };
+        let mut resolved = format!("{:#06x}", target);
+        if let Some(name) = symbols.names_at(target).first() {
+            resolved = format!("{} <{}>", resolved, name);
+        }
+        match self.branch_offset() {
+            Some(offset) => text.replace(&relative(offset), &resolved),
+            None => text.replace(&format!("{:#06x}", target), &resolved),
+        }
+    }
+
+    /// Whether this can move IP anywhere but the next instruction:
+    /// branches, calls, `ret` and writes to IP.
+    pub fn is_control_flow(&self) -> bool {
+        self.register_effects().1.contains(&Simple::INSTRUCTION_POINTER)
+    }
+
+    /// The registers this reads and writes, including implicit ones such as
+    /// the flags, SP for stack operations and IP for control flow. Memory
+    /// operands aren't included.
+    pub fn register_effects(&self) -> (Vec<usize>, Vec<usize>) {
+        use Instruction::*;
+        const SP: usize = Simple::STACK_POINTER;
+        const IP: usize = Simple::INSTRUCTION_POINTER;
+        const FLAGS: usize = Simple::FLAG_REGISTER;
+        let uses_carry = |op: usize| op == 9 || op == 10;
+        let branch_reads = |cond: usize| if cond == 13 { vec![] } else { vec![FLAGS] };
+        let target_reads = |target: JumpTarget| match target {
+            JumpTarget::Register(rd) |
+            JumpTarget::Indirect(rd) |
+            JumpTarget::DoubleIndirect(rd) => vec![rd],
+            JumpTarget::Immediate(_) => vec![],
+            JumpTarget::Relative(_) => vec![IP],
+        };
+        let (mut reads, mut writes) = match *self {
+            Zero | Nop | Compressed(_) | Halt | Illegal(_) => (vec![], vec![]),
+            Not { rd } | Neg { rd } | Inc { rd } | Dec { rd } => (vec![rd], vec![rd]),
+            Abs { rd } | SNeg { rd } => (vec![rd], vec![rd, FLAGS]),
+            LoadFlags { rd } => (vec![FLAGS], vec![rd]),
+            StoreFlags { rd } => (vec![rd], vec![FLAGS]),
+            Push { rd } => (vec![rd, SP], vec![SP]),
+            Pop { rd } => (vec![SP], vec![rd, SP]),
+            Alu { op, rd, rs } => {
+                let mut reads = vec![rd, rs];
+                if uses_carry(op) {
+                    reads.push(FLAGS);
                 }
-                true
+                let writes = if op == 11 { vec![FLAGS] } else { vec![rd, FLAGS] };
+                (reads, writes)
             }
-            0b0111 => { // call? relative
-                // COPYPASTE from 0b0011
-                let cond = (instruction >> 8) & 0b1111;
-                let target = (instruction & 0b1111_1111) as i8 as i16 as usize;
-                self.advance_ip(2);
-                if self.should_jump(cond) {
-                    eprintln!("call relative {}", target as i16);
-                    self.push(self.regfile[Self::INSTRUCTION_POINTER]);
-                    self.advance_ip(target);
+            AluImmediate { op, rd, .. } => {
+                let mut reads = vec![rd];
+                if uses_carry(op) {
+                    reads.push(FLAGS);
                 }
-                true
+                let writes = if op == 11 { vec![FLAGS] } else { vec![rd, FLAGS] };
+                (reads, writes)
             }
-            0b1000 => { // mov rN, i8
-                let rd = (instruction >> 8) & 0b1111;
-                let n = instruction & 0b1111_1111;
-                self.regfile[rd] = n as u16;
-                self.advance_ip(2);
-                true
+            Jump { cond, target } => {
+                let mut reads = branch_reads(cond);
+                reads.extend(target_reads(target));
+                (reads, vec![IP])
             }
-            0b1001 => { // mov rN, i16
-                let rd = (instruction >> 8) & 0b1111;
-                let n = self.read_16(self.ip().wrapping_add(2));
-                self.regfile[rd] = n;
-                self.advance_ip(4);
-                true
+            JumpRelative { cond, .. } => {
+                let mut reads = branch_reads(cond);
+                reads.push(IP);
+                (reads, vec![IP])
             }
-            0b1010 => { // misc
-                match instruction & 0b1111_1111_1111 {
-                    1 => {
-                        self.regfile[Self::INSTRUCTION_POINTER] = self.pop();
-                        true
-                    }
-                    _ => todo!(),
-                }
+            Call { cond, target } => {
+                let mut reads = branch_reads(cond);
+                reads.extend(target_reads(target));
+                reads.extend(&[IP, SP]);
+                (reads, vec![SP, IP])
             }
-            0b1011 => { // mov rNpN, rNpN
-                let rd = (instruction >> 8) & 0b1111;
-                let rs = (instruction >> 4) & 0b1111;
-                let pd = (instruction >> 2) & 0b11;
-                let ps = instruction & 0b11;
-                eprintln!("mov r{}, r{}", rd + pd * 16, rs + ps * 16);
-                self.regfile[rd + pd * 16] = self.regfile[rs + ps * 16];
-                self.advance_ip(2);
-                true
+            CallRelative { cond, .. } => {
+                let mut reads = branch_reads(cond);
+                reads.extend(&[IP, SP]);
+                (reads, vec![SP, IP])
             }
-            // 0b1100 - 0b1111 empty
-            _ => {
-                todo!();
+            Load { rd, rs, ro } => (vec![rs, ro], vec![rd]),
+            LoadDisplaced { rd, rs, .. } => (vec![rs], vec![rd]),
+            Store { rd, rs, ro } => (vec![rd, rs, ro], vec![]),
+            StoreByte { rd, rs } => (vec![rd, rs], vec![]),
+            CompareImmediate { rd, .. } => (vec![rd], vec![FLAGS]),
+            HaltWithCode { rd } => (vec![rd], vec![]),
+            MovI8 { rd, .. } | MovI16 { rd, .. } | ReadCycles { rd } => (vec![], vec![rd]),
+            CompareJump { ra, rb, .. } => (vec![ra, rb, IP], vec![IP]),
+            LoadAcc32 { .. } => (vec![], vec![Simple::ACCUMULATOR_HI, Simple::ACCUMULATOR_LO]),
+            PushMultiple { mask } => {
+                let mut reads = mask_registers(mask);
+                reads.push(SP);
+                (reads, vec![SP])
             }
-        }
+            PopMultiple { mask } => {
+                let mut writes = mask_registers(mask);
+                writes.push(SP);
+                (vec![SP], writes)
+            }
+            Cas { ra, re, rn } => (vec![ra, re, rn], vec![FLAGS]),
+            FixedMul { rd, rs, .. } => (
+                vec![rd, rs],
+                vec![rd, Simple::ACCUMULATOR_HI, Simple::ACCUMULATOR_LO],
+            ),
+            JumpRegister { rd, .. } => (vec![rd, IP], vec![IP]),
+            Ret => (vec![SP], vec![SP, IP]),
+            Mov { rd, rs } => (vec![rs], vec![rd]),
+        };
+        reads.sort_unstable();
+        reads.dedup();
+        writes.sort_unstable();
+        writes.dedup();
+        (reads, writes)
+    }
+}
+
+/// Shorthand for decoding `word` and asking for its `register_effects`.
+pub fn register_effects(word: u16) -> (Vec<usize>, Vec<usize>) {
+    Instruction::decode(word, 0).register_effects()
+}
+
+fn condition_suffix(cond: usize) -> Option<&'static str> {
+    match cond {
+        1 => Some("a"),
+        2 => Some("ae"),
+        3 => Some("b"),
+        4 => Some("be"),
+        5 => Some("g"),
+        6 => Some("ge"),
+        7 => Some("l"),
+        8 => Some("le"),
+        9 => Some("z"),
+        10 => Some("nz"),
+        11 => Some("o"),
+        12 => Some("no"),
+        13 => Some(""),
+        _ => None,
+    }
+}
+
+/// The registers selected by a `pushm`/`popm` mask, in ascending order.
+fn mask_registers(mask: u16) -> Vec<usize> {
+    (0..16).filter(|reg| mask & 1 << reg != 0).collect()
+}
+
+fn register_list(mask: u16) -> String {
+    let registers: Vec<String> = mask_registers(mask).iter()
+        .map(|reg| format!("r{}", reg))
+        .collect();
+    format!("{{{}}}", registers.join(", "))
+}
+
+fn condition_holds(cond: usize, flags: alu::Flags) -> bool {
+    let zf = flags.contains(alu::ZF);
+    let cf = flags.contains(alu::CF);
+    let of = flags.contains(alu::OF);
+    let sf = flags.contains(alu::SF);
+    match cond {
+        1 => !zf && !cf,
+        2 => !cf,
+        3 => cf,
+        4 => cf || zf,
+        5 => !zf && sf == of,
+        6 => sf == of,
+        7 => sf != of,
+        8 => zf || sf != of,
+        9 => zf,
+        10 => !zf,
+        11 => of,
+        12 => !of,
+        13 => true,
+        _ => false,
+    }
+}
+
+fn branch_mnemonic(base: &str, cond: usize) -> String {
+    match (base, condition_suffix(cond)) {
+        ("j", Some("")) => "jmp".to_string(),
+        (_, Some(suffix)) => format!("{}{}", base, suffix),
+        (_, None) => format!("{}?{}", base, cond),
+    }
+}
+
+fn alu_mnemonic(op: usize) -> String {
+    match alu::mnemonic(op) {
+        Some(name) => name.to_string(),
+        None => format!("alu{}", op),
+    }
+}
+
+/// Instruction encoding constants, shared by `Instruction::decode` and
+/// `Instruction::encode`. Words are laid out as a 4-bit class in the top
+/// nibble followed by three 4-bit fields, with the exceptions noted.
+pub mod opcodes {
+    pub const CLASS_SHIFT: u16 = 12;
+
+    /// Instruction classes, selected by the top nibble.
+    pub const ALU: u16 = 0b0000;
+    pub const JUMP: u16 = 0b0001;
+    pub const ALU_IMMEDIATE: u16 = 0b0010;
+    pub const JUMP_RELATIVE: u16 = 0b0011;
+    pub const LOAD: u16 = 0b0100;
+    pub const STORE: u16 = 0b0101;
+    pub const CALL: u16 = 0b0110;
+    pub const CALL_RELATIVE: u16 = 0b0111;
+    pub const MOV_I8: u16 = 0b1000;
+    pub const MOV_I16: u16 = 0b1001;
+    pub const MISC: u16 = 0b1010;
+    /// Fields are `rd`, `rs`, then 2-bit register planes for each.
+    pub const MOV: u16 = 0b1011;
+    pub const CAS: u16 = 0b1100;
+    /// `rd`, then a nonzero bit and a 7-bit offset.
+    pub const JUMP_REGISTER: u16 = 0b1101;
+    pub const LOAD_DISPLACED: u16 = 0b1110;
+
+    /// One-operand ops: ALU-class words `0x00or`.
+    pub const NOT: u16 = 1;
+    pub const NEG: u16 = 2;
+    pub const PUSH: u16 = 3;
+    pub const POP: u16 = 4;
+    pub const INC: u16 = 5;
+    pub const DEC: u16 = 6;
+    pub const ABS: u16 = 7;
+    pub const SNEG: u16 = 8;
+    pub const LAHF: u16 = 9;
+    pub const SAHF: u16 = 10;
+
+    /// Jump and call target modes, in the low nibble.
+    pub const TARGET_REGISTER: u16 = 0;
+    pub const TARGET_INDIRECT: u16 = 1;
+    pub const TARGET_IMMEDIATE: u16 = 2;
+    pub const TARGET_RELATIVE: u16 = 3;
+    pub const TARGET_DOUBLE_INDIRECT: u16 = 4;
+
+    /// Misc sub-ops, in bits 8-11.
+    pub const MISC_CONTROL: u16 = 0;
+    pub const MISC_FMUL: u16 = 1;
+    pub const MISC_FMULU: u16 = 2;
+    pub const MISC_RDCYC: u16 = 3;
+    /// Followed by a word holding the condition in its top nibble and a
+    /// 12-bit offset.
+    pub const MISC_CMPJ: u16 = 4;
+    /// Followed by a word with a bit set for each of r0-r15 to push or
+    /// pop.
+    pub const MISC_PUSHM: u16 = 5;
+    pub const MISC_POPM: u16 = 6;
+    /// Followed by two words of immediate.
+    pub const MISC_LDACC: u16 = 7;
+    pub const MISC_STB: u16 = 8;
+    /// Followed by a word of immediate.
+    pub const MISC_CMPI: u16 = 9;
+    pub const MISC_HLTR: u16 = 10;
+
+    /// `MISC_CONTROL` operations, in the low byte.
+    pub const HALT: u16 = 0;
+    pub const RET: u16 = 1;
+    pub const NOP: u16 = 2;
+    pub const CMODE_ON: u16 = 3;
+    pub const CMODE_OFF: u16 = 4;
+
+    /// Compressed-mode bytes: an op in the high nibble and a register in
+    /// the low one, or `0b1nnn_rrrr` for `mov rN, n`.
+    pub const COMPRESSED_INC: u8 = 0x0;
+    pub const COMPRESSED_DEC: u8 = 0x1;
+    pub const COMPRESSED_PUSH: u8 = 0x2;
+    pub const COMPRESSED_POP: u8 = 0x3;
+    pub const COMPRESSED_NOT: u8 = 0x4;
+    pub const COMPRESSED_NEG: u8 = 0x5;
+    /// Control bytes, with the whole byte as the op.
+    pub const COMPRESSED_OFF: u8 = 0x60;
+    pub const COMPRESSED_HALT: u8 = 0x61;
+    pub const COMPRESSED_MOV: u8 = 0x80;
+}
+
+/// A code that doesn't name any `OpcodeClass` or `Condition`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownCode(pub u8);
+
+impl fmt::Display for UnknownCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown code {:#x}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownCode {}
+
+/// Where and why `decode_all` couldn't decode an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    UndefinedOpcode { addr: usize, word: u16 },
+    /// The buffer ends partway through the instruction at `addr`.
+    Truncated { addr: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::UndefinedOpcode { addr, word } =>
+                write!(f, "undefined opcode {:#06x} at {:#06x}", word, addr),
+            DecodeError::Truncated { addr } =>
+                write!(f, "truncated instruction at {:#06x}", addr),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// An instruction class, as selected by the top nibble of a word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpcodeClass {
+    Alu,
+    Jump,
+    AluImmediate,
+    JumpRelative,
+    Load,
+    Store,
+    Call,
+    CallRelative,
+    MovI8,
+    MovI16,
+    Misc,
+    Mov,
+    Cas,
+    JumpRegister,
+    LoadDisplaced,
+}
+
+impl TryFrom<u8> for OpcodeClass {
+    type Error = UnknownCode;
+
+    fn try_from(code: u8) -> Result<Self, UnknownCode> {
+        use OpcodeClass::*;
+        Ok(match code as u16 {
+            opcodes::ALU => Alu,
+            opcodes::JUMP => Jump,
+            opcodes::ALU_IMMEDIATE => AluImmediate,
+            opcodes::JUMP_RELATIVE => JumpRelative,
+            opcodes::LOAD => Load,
+            opcodes::STORE => Store,
+            opcodes::CALL => Call,
+            opcodes::CALL_RELATIVE => CallRelative,
+            opcodes::MOV_I8 => MovI8,
+            opcodes::MOV_I16 => MovI16,
+            opcodes::MISC => Misc,
+            opcodes::MOV => Mov,
+            opcodes::CAS => Cas,
+            opcodes::JUMP_REGISTER => JumpRegister,
+            opcodes::LOAD_DISPLACED => LoadDisplaced,
+            _ => return Err(UnknownCode(code)),
+        })
+    }
+}
+
+/// A branch condition, as taken by jumps, calls and `cmpj`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Above = 1,
+    AboveEqual,
+    Below,
+    BelowEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Zero,
+    NotZero,
+    Overflow,
+    NoOverflow,
+    Always,
+}
+
+impl Condition {
+    /// Whether a branch on this condition is taken with `flags`.
+    pub fn holds(self, flags: alu::Flags) -> bool {
+        condition_holds(self as usize, flags)
+    }
+}
+
+impl TryFrom<u8> for Condition {
+    type Error = UnknownCode;
+
+    fn try_from(code: u8) -> Result<Self, UnknownCode> {
+        use Condition::*;
+        Ok(match code {
+            1 => Above,
+            2 => AboveEqual,
+            3 => Below,
+            4 => BelowEqual,
+            5 => Greater,
+            6 => GreaterEqual,
+            7 => Less,
+            8 => LessEqual,
+            9 => Zero,
+            10 => NotZero,
+            11 => Overflow,
+            12 => NoOverflow,
+            13 => Always,
+            _ => return Err(UnknownCode(code)),
+        })
+    }
+}
+
+impl fmt::Display for JumpTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JumpTarget::Register(rd) => write!(f, "r{}", rd),
+            JumpTarget::Indirect(rd) => write!(f, "[r{}]", rd),
+            JumpTarget::Immediate(n) => write!(f, "{:#06x}", n),
+            JumpTarget::Relative(n) => write!(f, "{}", relative(*n)),
+            JumpTarget::DoubleIndirect(rd) => write!(f, "[[r{}]]", rd),
+        }
+    }
+}
+
+fn relative(offset: i16) -> String {
+    if offset < 0 {
+        format!("[ip - {}]", -(offset as i32))
+    } else {
+        format!("[ip + {}]", offset)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+        match *self {
+            Zero => write!(f, "zero"),
+            Not { rd } => write!(f, "not r{}", rd),
+            Neg { rd } => write!(f, "neg r{}", rd),
+            Push { rd } => write!(f, "push r{}", rd),
+            Pop { rd } => write!(f, "pop r{}", rd),
+            Inc { rd } => write!(f, "inc r{}", rd),
+            Dec { rd } => write!(f, "dec r{}", rd),
+            Abs { rd } => write!(f, "abs r{}", rd),
+            SNeg { rd } => write!(f, "sneg r{}", rd),
+            LoadFlags { rd } => write!(f, "lahf r{}", rd),
+            StoreFlags { rd } => write!(f, "sahf r{}", rd),
+            Alu { op, rd, rs } =>
+                write!(f, "{} r{}, r{}", alu_mnemonic(op), rd, rs),
+            Jump { cond, target } =>
+                write!(f, "{} {}", branch_mnemonic("j", cond), target),
+            AluImmediate { op, rd, n } =>
+                write!(f, "{} r{}, {}", alu_mnemonic(op), rd, n),
+            JumpRelative { cond, offset } =>
+                write!(f, "{} {}", branch_mnemonic("j", cond), relative(offset.into())),
+            Load { rd, rs, ro } => write!(f, "mov r{}, [r{} + r{}]", rd, rs, ro),
+            Store { rd, rs, ro } => write!(f, "mov [r{} + r{}], r{}", rd, ro, rs),
+            LoadDisplaced { rd, rs, disp } if disp < 0 =>
+                write!(f, "mov r{}, [r{} - {}]", rd, rs, -disp),
+            LoadDisplaced { rd, rs, disp } => write!(f, "mov r{}, [r{} + {}]", rd, rs, disp),
+            Call { cond, target } =>
+                write!(f, "{} {}", branch_mnemonic("call", cond), target),
+            CallRelative { cond, offset } =>
+                write!(f, "{} {}", branch_mnemonic("call", cond), relative(offset.into())),
+            MovI8 { rd, n } => write!(f, "mov r{}, {}", rd, n),
+            MovI16 { rd, n } => write!(f, "mov r{}, {}", rd, n),
+            Cas { ra, re, rn } => write!(f, "cas [r{}], r{}, r{}", ra, re, rn),
+            JumpRegister { rd, nonzero, offset } => {
+                let mnemonic = if nonzero { "jrnz" } else { "jrz" };
+                write!(f, "{} r{}, {}", mnemonic, rd, relative(offset.into()))
+            }
+            FixedMul { rd, rs, signed } => {
+                let mnemonic = if signed { "fmul" } else { "fmulu" };
+                write!(f, "{} r{}, r{}", mnemonic, rd, rs)
+            }
+            ReadCycles { rd } => write!(f, "rdcyc r{}", rd),
+            StoreByte { rd, rs } => write!(f, "movb [r{}], r{}", rd, rs),
+            CompareImmediate { rd, n } => write!(f, "cmpi r{}, {}", rd, n),
+            CompareJump { cond, ra, rb, offset } => write!(f, "{} r{}, r{}, {}",
+                branch_mnemonic("cmpj", cond), ra, rb, relative(offset)),
+            PushMultiple { mask } => write!(f, "pushm {}", register_list(mask)),
+            PopMultiple { mask } => write!(f, "popm {}", register_list(mask)),
+            Nop => write!(f, "nop"),
+            Compressed(on) => write!(f, "cmode {}", if on { "on" } else { "off" }),
+            Halt => write!(f, "hlt"),
+            HaltWithCode { rd } => write!(f, "hlt r{}", rd),
+            Ret => write!(f, "ret"),
+            Mov { rd, rs } => write!(f, "mov r{}, r{}", rd, rs),
+            LoadAcc32 { n } => write!(f, "mov acc, {:#010x}", n),
+            Illegal(word) => write!(f, ".word {:#06x}", word),
+        }
+    }
+}
+
+/// One line of a program for `assemble`: an instruction or a `.word` or
+/// `.byte` directive embedding data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line {
+    Instruction(Instruction),
+    Word(u16),
+    Byte(u8),
+}
+
+impl From<Instruction> for Line {
+    fn from(instruction: Instruction) -> Self {
+        Line::Instruction(instruction)
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Line::Instruction(instruction) => write!(f, "{}", instruction),
+            Line::Word(n) => write!(f, ".word {:#06x}", n),
+            Line::Byte(n) => write!(f, ".byte {:#04x}", n),
+        }
+    }
+}
+
+/// Assemble `lines` into a program image. Directives emit their data at
+/// the current position, big-endian, with no alignment padding.
+pub fn assemble(lines: &[Line]) -> Vec<u8> {
+    let mut program = Vec::new();
+    for line in lines {
+        match *line {
+            Line::Instruction(instruction) => {
+                for word in instruction.encode() {
+                    program.extend_from_slice(&word.to_be_bytes());
+                }
+            }
+            Line::Word(n) => program.extend_from_slice(&n.to_be_bytes()),
+            Line::Byte(n) => program.push(n),
+        }
+    }
+    program
+}
+
+/// Like `assemble`, but put runs of instructions that have compressed
+/// forms in compressed mode where that saves space. Branch offsets aren't
+/// adjusted for the shorter code, so it suits straight-line code, and
+/// full-width instructions may end up at odd addresses.
+pub fn assemble_compressed(instructions: &[Instruction]) -> Vec<u8> {
+    let mut program = Vec::new();
+    let mut i = 0;
+    while i < instructions.len() {
+        let run = instructions[i..].iter()
+            .take_while(|instruction| instruction.encode_compressed().is_some())
+            .count();
+        // Switching on and off again costs three bytes.
+        if run < 4 {
+            program.extend(assemble(&[instructions[i].into()]));
+            i += 1;
+            continue;
+        }
+        program.extend(assemble(&[Instruction::Compressed(true).into()]));
+        program.extend(instructions[i..i + run].iter()
+            .filter_map(|instruction| instruction.encode_compressed()));
+        i += run;
+        if i < instructions.len() {
+            program.push(opcodes::COMPRESSED_OFF);
+        }
+    }
+    program
+}
+
+/// Decode `bytes` as consecutive instructions from offset 0. An undefined
+/// opcode is reported and skipped a word at a time; a truncated
+/// instruction at the end is reported and ends the list.
+pub fn decode_all(bytes: &[u8]) -> Vec<Result<Instruction, DecodeError>> {
+    let word_at = |addr: usize| match bytes.get(addr..addr + 2) {
+        Some(word) => u16::from_be_bytes([word[0], word[1]]),
+        None => 0,
+    };
+    let mut instructions = Vec::new();
+    let mut addr = 0;
+    while addr < bytes.len() {
+        let instruction = Instruction::decode_wide(
+            word_at(addr), word_at(addr + 2), word_at(addr + 4));
+        if addr + instruction.size() > bytes.len() {
+            instructions.push(Err(DecodeError::Truncated { addr }));
+            break;
+        }
+        instructions.push(match instruction {
+            Instruction::Illegal(word) => Err(DecodeError::UndefinedOpcode { addr, word }),
+            _ => Ok(instruction),
+        });
+        addr += instruction.size();
+    }
+    instructions
+}
+
+pub struct Simple {
+    regfile: [u16; Self::REGISTER_COUNT],
+    ram: Box<dyn Memory>,
+    segmented: bool,
+    strict_alignment: bool,
+    regions: Vec<Region>,
+    mapped: Vec<Range<usize>>,
+    code: Option<(usize, Rc<[u8]>)>,
+    last_instruction: Option<(u16, u16)>,
+    last_branch: Option<BranchInfo>,
+    executed: Option<(u16, Instruction)>,
+    halt_policy: HaltPolicy,
+    alu_immediate_mode: ImmediateMode,
+    mov_immediate_mode: ImmediateMode,
+    breakpoints: Vec<Breakpoint>,
+    stopped_at_breakpoint: bool,
+    register_breakpoints: Vec<(usize, u16)>,
+    profile: Option<HashMap<u16, u64>>,
+    output: Box<dyn Write>,
+    input: RefCell<Option<Box<dyn Read>>>,
+    console_mode: ConsoleMode,
+    console_line: Vec<u8>,
+    console_radix: u32,
+    console_width: usize,
+    memory_accesses: Cell<u64>,
+    wait_states: Vec<(Range<usize>, u64)>,
+    pending_wait: Cell<u64>,
+    memory_quota: Option<u64>,
+    trace: bool,
+    signed_registers: bool,
+    step_limit: u64,
+    written_registers: Option<u64>,
+    cycles: u64,
+    halted: bool,
+    exit_code: Option<u16>,
+    compressed: bool,
+    trace_writer: Option<Box<dyn Write>>,
+    detect_ip_wrap: bool,
+    sanity_checks: bool,
+    stack_region: Option<Range<usize>>,
+    output_callback: Option<Box<dyn FnMut(u16)>>,
+    interrupts: InterruptController,
+    symbols: SymbolTable,
+    illegal_handler: Option<IllegalHandler>,
+    halt_callback: Option<HaltCallback>,
+    memory_hook: RefCell<Option<MemoryHook>>,
+    fetch_hook: Option<FetchHook>,
+    register_hook: Option<RegisterHook>,
+    stats: Cell<Option<RunStats>>,
+    overrun: u64,
+    watchdog: Option<Watchdog>,
+}
+
+impl Simple {
+    const STACK_POINTER: usize = 15;
+    const INSTRUCTION_POINTER: usize = 16;
+    const FLAG_REGISTER: usize = 17;
+    const ACCUMULATOR_HI: usize = 18;
+    const ACCUMULATOR_LO: usize = 19;
+    const CODE_SEGMENT: usize = 20;
+    const DATA_SEGMENT: usize = 21;
+    const STACK_SEGMENT: usize = 22;
+    pub const REGISTER_COUNT: usize = 16 * 4;
+    pub const DEFAULT_STEP_LIMIT: u64 = 10_000_000;
+    /// Console output, shown according to the `ConsoleMode`.
+    pub const CONSOLE_PORT: usize = 0xFF01;
+    /// Writes here print as `0x`-prefixed hex, one per line.
+    pub const HEX_PORT: usize = 0xFF02;
+    /// Writes here print as unsigned decimal, one per line.
+    pub const DECIMAL_PORT: usize = 0xFF03;
+    /// The low byte of writes here is output as a raw character.
+    pub const CHAR_PORT: usize = 0xFF04;
+    /// Reads take the next byte of console input, or `0xFFFF` at the end
+    /// of input or if there is none.
+    pub const INPUT_PORT: usize = 0xFF06;
+    /// Start of a 64x64 1-bit framebuffer in ordinary RAM, one row per 8
+    /// bytes, most significant bit leftmost.
+    pub const FRAMEBUFFER: usize = 0xF000;
+    pub const FRAMEBUFFER_WIDTH: usize = 64;
+    pub const FRAMEBUFFER_HEIGHT: usize = 64;
+    /// Interrupt controller mask; reads and writes the mask.
+    pub const IRQ_MASK_PORT: usize = 0xFF10;
+    /// Reads the pending lines; writes raise the lines set in the value.
+    pub const IRQ_PENDING_PORT: usize = 0xFF12;
+    /// Writing a line number here ends its service.
+    pub const IRQ_ACK_PORT: usize = 0xFF14;
+    /// Handler addresses, one word per interrupt line. Delivering an
+    /// interrupt pushes IP and jumps to the line's handler, which returns
+    /// with `ret`.
+    pub const IRQ_VECTORS: usize = 0xFFC0;
+    /// The initial IP, followed by the initial SP, for `boot` and `reset`.
+    pub const RESET_VECTOR: usize = 0xFFFC;
+
+    pub fn new() -> Self {
+        Simple {
+            regfile: [0; Self::REGISTER_COUNT],
+            ram: Box::new(vec![0; 65536]),
+            segmented: false,
+            strict_alignment: false,
+            regions: Vec::new(),
+            mapped: Vec::new(),
+            code: None,
+            last_instruction: None,
+            last_branch: None,
+            executed: None,
+            halt_policy: HaltPolicy::default(),
+            alu_immediate_mode: ImmediateMode::default(),
+            mov_immediate_mode: ImmediateMode::default(),
+            breakpoints: Vec::new(),
+            stopped_at_breakpoint: false,
+            register_breakpoints: Vec::new(),
+            profile: None,
+            output: Box::new(io::stderr()),
+            input: RefCell::new(None),
+            console_mode: ConsoleMode::default(),
+            console_line: Vec::new(),
+            console_radix: 10,
+            console_width: 0,
+            memory_accesses: Cell::new(0),
+            wait_states: Vec::new(),
+            pending_wait: Cell::new(0),
+            memory_quota: None,
+            trace: true,
+            step_limit: Self::DEFAULT_STEP_LIMIT,
+            written_registers: None,
+            cycles: 0,
+            halted: false,
+            exit_code: None,
+            compressed: false,
+            trace_writer: None,
+            signed_registers: false,
+            detect_ip_wrap: false,
+            sanity_checks: false,
+            stack_region: None,
+            output_callback: None,
+            interrupts: InterruptController::default(),
+            symbols: SymbolTable::new(),
+            illegal_handler: None,
+            halt_callback: None,
+            memory_hook: RefCell::new(None),
+            fetch_hook: None,
+            register_hook: None,
+            stats: Cell::new(None),
+            overrun: 0,
+            watchdog: None,
+        }
+    }
+
+    /// Use `memory` as the address space instead of a flat 64KB array.
+    pub fn with_memory(memory: Box<dyn Memory>) -> Self {
+        Simple {
+            ram: memory,
+            ..Self::new()
+        }
+    }
+
+    /// Start from a complete 64KB memory image, code and data included,
+    /// without copying it. IP starts at 0.
+    pub fn with_ram(image: Box<[u8]>) -> Self {
+        assert_eq!(image.len(), 65536, "memory image must be 64KB");
+        Self::with_memory(Box::new(image.into_vec()))
+    }
+
+    /// Like `with_ram`, but take the initial IP and SP from the image's
+    /// `RESET_VECTOR`, so a bootable image picks its own entry point.
+    pub fn boot(image: Box<[u8]>) -> Self {
+        let mut simple = Self::with_ram(image);
+        simple.reset();
+        simple
+    }
+
+    /// Clear the registers and run state, keeping memory and settings, and
+    /// restart from the IP and SP in the `RESET_VECTOR`.
+    pub fn reset(&mut self) {
+        self.cycles = 0;
+        self.overrun = 0;
+        self.restart();
+    }
+
+    /// Like `reset`, but leave the cycle count running, as a watchdog reset
+    /// in the middle of `emulate` must.
+    fn restart(&mut self) {
+        self.regfile = [0; Self::REGISTER_COUNT];
+        self.regfile[Self::INSTRUCTION_POINTER] = self.peek_16(Self::RESET_VECTOR);
+        self.regfile[Self::STACK_POINTER] = self.peek_16(Self::RESET_VECTOR + 2);
+        self.halted = false;
+        self.interrupts = InterruptController::default();
+        self.last_instruction = None;
+        self.last_branch = None;
+        self.stopped_at_breakpoint = false;
+        self.compressed = false;
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.last_kick = self.cycles;
+        }
+    }
+
+    /// Run `program` on a fresh machine for at most `max_steps` instructions
+    /// and return the final register file.
+    pub fn load_and_run(program: &[u8], max_steps: usize)
+            -> Result<[u16; Self::REGISTER_COUNT], EmuError> {
+        let mut simple = Self::new();
+        simple.load_program(program.to_vec());
+        simple.set_step_limit(max_steps as u64);
+        match simple.run() {
+            StopReason::Fault(err) => Err(err),
+            _ => Ok(simple.regfile),
+        }
+    }
+
+    pub fn with_halt_policy(halt_policy: HaltPolicy) -> Self {
+        Simple {
+            halt_policy,
+            ..Self::new()
+        }
+    }
+
+    /// When set, word accesses to odd addresses fault with
+    /// `EmuError::UnalignedAccess` instead of spanning two cells, and
+    /// fetching from an odd IP faults with `EmuError::MisalignedFetch`.
+    pub fn set_strict_alignment(&mut self, strict: bool) {
+        self.strict_alignment = strict;
+    }
+
+    /// Select whether the 4-bit immediate of the `0b0010` ALU form is zero-
+    /// or sign-extended. Zero extension is the default, so `add r1, 0xF`
+    /// adds 15; with sign extension it adds -1.
+    pub fn set_alu_immediate_mode(&mut self, mode: ImmediateMode) {
+        self.alu_immediate_mode = mode;
+    }
+
+    /// Likewise for the 8-bit immediate of `0b1000` `mov rN, i8`, so
+    /// `mov r1, 0xFF` loads either 255 or `0xFFFF`.
+    pub fn set_mov_immediate_mode(&mut self, mode: ImmediateMode) {
+        self.mov_immediate_mode = mode;
+    }
+
+    /// Turn the per-instruction trace on stderr on or off.
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = enabled;
+    }
+
+    /// Show r0-r15 as signed decimal rather than hex in traces and
+    /// `dump_registers`, so `0xFFFB` reads as `-5`.
+    pub fn set_signed_registers(&mut self, signed: bool) {
+        self.signed_registers = signed;
+    }
+
+    /// r0-r15 on one line, as in `r0=0000 r1=fffe ...`, or `r1=-2` with
+    /// `set_signed_registers`.
+    pub fn dump_registers(&self) -> String {
+        self.regfile[..16].iter()
+            .enumerate()
+            .map(|(reg, &value)| format!("r{}={}", reg, self.format_register(value)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn format_register(&self, value: u16) -> String {
+        if self.signed_registers {
+            (value as i16).to_string()
+        } else {
+            format!("{:04x}", value)
+        }
+    }
+
+    /// The most instructions a single `run` will execute before stopping
+    /// with `StopReason::StepLimit`. Defaults to `DEFAULT_STEP_LIMIT`.
+    pub fn set_step_limit(&mut self, limit: u64) {
+        self.step_limit = limit;
+    }
+
+    /// Fault with `EmuError::UninitializedRegister` when an instruction reads
+    /// a register nothing has written since this was turned on. SP, IP and
+    /// the flags always count as initialized.
+    pub fn set_uninitialized_check(&mut self, enabled: bool) {
+        self.written_registers = if enabled {
+            Some(1 << Self::STACK_POINTER |
+                1 << Self::INSTRUCTION_POINTER |
+                1 << Self::FLAG_REGISTER)
+        } else {
+            None
+        };
+    }
+
+    /// Send console output to `output` instead of stderr.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /// Take console input for `INPUT_PORT` from `input`.
+    pub fn set_input(&mut self, input: Box<dyn Read>) {
+        *self.input.get_mut() = Some(input);
+    }
+
+    fn input_read(&self) -> u16 {
+        let mut byte = [0];
+        match self.input.borrow_mut().as_mut().map(|input| input.read(&mut byte)) {
+            Some(Ok(1)) => byte[0] as u16,
+            _ => 0xFFFF,
+        }
+    }
+
+    /// Run with console input from `input` and output to `output`.
+    pub fn run_with_io(&mut self, input: impl Read + 'static, output: impl Write + 'static)
+            -> StopReason {
+        self.set_input(Box::new(input));
+        self.set_output(Box::new(output));
+        self.run()
+    }
+
+    /// Call `f` with each value written to the console port, in addition
+    /// to the usual output.
+    pub fn set_output_callback(&mut self, f: Box<dyn FnMut(u16)>) {
+        self.output_callback = Some(f);
+    }
+
+    pub fn set_console_mode(&mut self, mode: ConsoleMode) {
+        self.console_mode = mode;
+    }
+
+    /// Print numeric console output in `radix` (2, 8, 10 or 16), zero
+    /// padded to `width` digits. Radixes other than 10 get a `0b`, `0o` or
+    /// `0x` prefix. The default is plain decimal.
+    pub fn set_console_format(&mut self, radix: u32, width: usize) {
+        assert!([2, 8, 10, 16].contains(&radix), "unsupported console radix {}", radix);
+        self.console_radix = radix;
+        self.console_width = width;
+    }
+
+    fn console_write(&mut self, value: u16) {
+        if let Some(callback) = &mut self.output_callback {
+            callback(value);
+        }
+        // Console output is best-effort; a broken sink shouldn't fault the
+        // machine.
+        match self.console_mode {
+            ConsoleMode::Numeric => {
+                let width = self.console_width;
+                let _ = match self.console_radix {
+                    2 => writeln!(self.output, "OUT: 0b{:01$b}", value, width),
+                    8 => writeln!(self.output, "OUT: 0o{:01$o}", value, width),
+                    16 => writeln!(self.output, "OUT: 0x{:01$x}", value, width),
+                    _ => writeln!(self.output, "OUT: {:01$}", value, width),
+                };
+            }
+            ConsoleMode::Ascii => {
+                self.console_line.push(value as u8);
+                if value as u8 == b'\n' {
+                    let _ = self.output.write_all(&self.console_line);
+                    self.console_line.clear();
+                }
+            }
+        }
+    }
+
+    /// Limit the number of data reads and writes a program may make, for
+    /// running untrusted code. `run` stops with `StopReason::QuotaExceeded`
+    /// once it's used up.
+    pub fn set_memory_quota(&mut self, quota: Option<u64>) {
+        self.memory_quota = quota;
+    }
+
+    /// Data reads and writes made so far (instruction fetches don't count).
+    pub fn memory_accesses(&self) -> u64 {
+        self.memory_accesses.get()
+    }
+
+    fn charge_memory_access(&self) -> Result<(), EmuError> {
+        let accesses = self.memory_accesses.get();
+        if let Some(quota) = self.memory_quota {
+            if accesses >= quota {
+                return Err(EmuError::QuotaExceeded);
+            }
+        }
+        self.memory_accesses.set(accesses + 1);
+        Ok(())
+    }
+
+    /// Charge `cycles` extra for each data read or write in `range`, as
+    /// for slow external RAM. The first range containing an address
+    /// decides.
+    pub fn add_wait_states(&mut self, range: Range<usize>, cycles: u64) {
+        self.wait_states.push((range, cycles));
+    }
+
+    fn wait(&self, address: usize) {
+        if let Some((_, cycles)) = self.wait_states.iter().find(|(r, _)| r.contains(&address)) {
+            self.pending_wait.set(self.pending_wait.get() + cycles);
+        }
+    }
+
+    /// Grow RAM to 1MB and form addresses x86-style as `(segment << 4) +
+    /// offset`. Fetches use the code segment (r20), loads and stores the
+    /// data segment (r21) and the stack the stack segment (r22); programs
+    /// set them with the register-plane `mov`. Addresses seen by regions,
+    /// MMIO and errors are physical.
+    pub fn enable_segmentation(&mut self) {
+        self.segmented = true;
+        if self.ram.size() < 1 << 20 {
+            let mut ram = vec![0; 1 << 20];
+            for (addr, byte) in ram.iter_mut().enumerate().take(self.ram.size()) {
+                *byte = self.ram.read(addr);
+            }
+            self.ram = Box::new(ram);
+        }
+    }
+
+    fn translate(&self, segment: usize, address: usize) -> usize {
+        if self.segmented {
+            let base = (self.regfile[segment] as usize) << 4;
+            (base + (address & 0xFFFF)) & 0xF_FFFF
+        } else {
+            address
+        }
+    }
+
+    /// Protect `range` with the `PERM_*` bits in `perms`. The first region
+    /// containing an address decides; memory outside every region is
+    /// unrestricted.
+    pub fn add_region(&mut self, range: Range<usize>, perms: u8) {
+        self.regions.push(Region { range, perms });
+    }
+
+    /// Declare `range` as backed by memory or devices. Once any range is
+    /// mapped, accesses and fetches touching an address outside all of
+    /// them fault with `EmuError::BusError`, ports included.
+    pub fn add_mapped_range(&mut self, range: Range<usize>) {
+        self.mapped.push(range);
+    }
+
+    fn check_mapped(&self, address: usize, width: usize) -> Result<(), EmuError> {
+        if self.mapped.is_empty() {
+            return Ok(());
+        }
+        for addr in (0..width).map(|i| (address + i) % self.ram.size()) {
+            if !self.mapped.iter().any(|range| range.contains(&addr)) {
+                return Err(EmuError::BusError { addr });
+            }
+        }
+        Ok(())
+    }
+
+    fn permits(&self, address: usize, perm: u8) -> bool {
+        match self.regions.iter().find(|r| r.range.contains(&address)) {
+            Some(region) => region.perms & perm != 0,
+            None => true,
+        }
+    }
+
+    /// Load `program` at address 0, returning its length in bytes.
+    pub fn load_program(&mut self, program: Vec<u8>) -> usize {
+        self.load_program_at(0, program, false)
+    }
+
+    /// Load `program` at `addr`, and make that the entry point if `entry`
+    /// is set. Like the rest of memory, it wraps around at the end of RAM.
+    /// Returns the number of bytes loaded.
+    pub fn load_program_at(&mut self, addr: u16, program: Vec<u8>, entry: bool) -> usize {
+        self.halted = false;
+        for (index, byte) in program.iter().enumerate() {
+            self.ram.write((addr as usize + index) % self.ram.size(), *byte);
+        }
+        if entry {
+            self.set_entry_point(addr);
+        }
+        program.len()
+    }
+
+    /// Start execution at `addr` rather than 0.
+    pub fn set_entry_point(&mut self, addr: u16) {
+        self.set_instruction_pointer(addr);
+    }
+
+    /// Panics if the memory backend isn't a flat array.
+    pub fn memory(&self) -> &[u8] {
+        self.ram.as_slice().expect("memory backend isn't a flat array")
+    }
+
+    /// Direct access to RAM. Like `raw_write_range`, writes through this
+    /// bypass MMIO and memory protection. Panics if the memory backend
+    /// isn't a flat array.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        self.ram.as_mut_slice().expect("memory backend isn't a flat array")
+    }
+
+    /// Copy `bytes` into RAM at `addr`, bypassing MMIO. For loaders and
+    /// debuggers, not program-visible stores. Fails with `OutOfRange` if
+    /// the bytes don't all fit, before writing anything.
+    pub fn raw_write_range(&mut self, addr: usize, bytes: &[u8]) -> Result<(), EmuError> {
+        if addr + bytes.len() > self.ram.size() {
+            return Err(EmuError::OutOfRange { addr: addr.max(self.ram.size()) });
+        }
+        for (index, byte) in bytes.iter().enumerate() {
+            self.ram.write(addr + index, *byte);
+        }
+        Ok(())
+    }
+
+    /// Apply byte fixups to RAM, bypassing MMIO. Fails with `OutOfRange`
+    /// for the first address past the end of RAM, before writing anything.
+    pub fn patch(&mut self, patches: &[(usize, u8)]) -> Result<(), EmuError> {
+        if let Some(&(addr, _)) = patches.iter().find(|&&(addr, _)| addr >= self.ram.size()) {
+            return Err(EmuError::OutOfRange { addr });
+        }
+        for &(addr, byte) in patches {
+            self.ram.write(addr, byte);
+        }
+        Ok(())
+    }
+
+    /// Render the framebuffer as text, `#` for set pixels and `.` for
+    /// clear ones, one line per row.
+    pub fn framebuffer_ascii(&self) -> String {
+        let row_bytes = Self::FRAMEBUFFER_WIDTH / 8;
+        let mut out = String::new();
+        for row in 0..Self::FRAMEBUFFER_HEIGHT {
+            for col in 0..Self::FRAMEBUFFER_WIDTH {
+                let byte = self.ram.read(Self::FRAMEBUFFER + row * row_bytes + col / 8);
+                out.push(if byte & 0x80 >> (col % 8) != 0 { '#' } else { '.' });
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn instruction_pointer(&self) -> u16 {
+        self.regfile[Self::INSTRUCTION_POINTER]
+    }
+
+    pub fn set_instruction_pointer(&mut self, addr: u16) {
+        self.halted = false;
+        self.regfile[Self::INSTRUCTION_POINTER] = addr;
+    }
+
+    fn ip(&self) -> usize {
+        self.regfile[Self::INSTRUCTION_POINTER] as usize
+    }
+
+    fn advance_ip(&mut self, amount: usize) {
+        self.regfile[Self::INSTRUCTION_POINTER] = 
+            self.regfile[Self::INSTRUCTION_POINTER].wrapping_add(amount as u16);
+    }
+
+    /// A copy of the whole register file, much cheaper than snapshotting
+    /// the machine when RAM doesn't matter.
+    pub fn save_registers(&self) -> [u16; Self::REGISTER_COUNT] {
+        self.regfile
+    }
+
+    /// Put back registers from `save_registers`. Memory is left alone.
+    pub fn restore_registers(&mut self, regs: [u16; Self::REGISTER_COUNT]) {
+        self.regfile = regs;
+        self.halted = false;
+    }
+
+    pub fn registers(&self) -> Registers {
+        let r = &self.regfile;
+        Registers {
+            r0: r[0],
+            r1: r[1],
+            r2: r[2],
+            r3: r[3],
+            r4: r[4],
+            r5: r[5],
+            r6: r[6],
+            r7: r[7],
+            r8: r[8],
+            r9: r[9],
+            r10: r[10],
+            r11: r[11],
+            r12: r[12],
+            r13: r[13],
+            r14: r[14],
+            r15: r[15],
+            sp: r[Self::STACK_POINTER],
+            ip: r[Self::INSTRUCTION_POINTER],
+            flags: self.flags(),
+        }
+    }
+
+    /// The 32-bit accumulator formed by the plane-1 register pair r18:r19
+    /// (high word first). Wide results such as multiplies land here.
+    pub fn acc32(&self) -> u32 {
+        ((self.regfile[Self::ACCUMULATOR_HI] as u32) << 16) |
+            self.regfile[Self::ACCUMULATOR_LO] as u32
+    }
+
+    pub fn set_acc32(&mut self, value: u32) {
+        self.regfile[Self::ACCUMULATOR_HI] = (value >> 16) as u16;
+        self.regfile[Self::ACCUMULATOR_LO] = value as u16;
+    }
+
+    fn flags(&self) -> alu::Flags {
+        alu::Flags::from_bits_retain(self.regfile[Self::FLAG_REGISTER])
+    }
+
+    #[cfg(test)]
+    fn zf(&self) -> bool {
+        self.flags().contains(alu::ZF)
+    }
+
+    fn cf(&self) -> bool {
+        self.flags().contains(alu::CF)
+    }
+
+    fn of(&self) -> bool {
+        self.flags().contains(alu::OF)
+    }
+
+    /// Whether the last ALU result carried or overflowed.
+    pub fn last_overflowed(&self) -> bool {
+        self.last_carried() || self.last_signed_overflowed()
+    }
+
+    /// Unsigned carry (or borrow) out of the last ALU result.
+    pub fn last_carried(&self) -> bool {
+        self.cf()
+    }
+
+    /// Signed overflow of the last ALU result.
+    pub fn last_signed_overflowed(&self) -> bool {
+        self.of()
+    }
+
+    #[cfg(test)]
+    fn sf(&self) -> bool {
+        self.flags().contains(alu::SF)
+    }
+
+    fn check_alignment(&self, address: usize) -> Result<(), EmuError> {
+        if self.strict_alignment && !address.is_multiple_of(2) {
+            return Err(EmuError::UnalignedAccess { addr: address });
+        }
+        Ok(())
+    }
+
+    /// A word at the last byte of memory takes its low byte from address 0.
+    fn peek_16(&self, address: usize) -> u16 {
+        ((self.ram.read(address) as u16) << 8) |
+            self.ram.read((address + 1) % self.ram.size()) as u16
+    }
+
+    /// Fetch instructions in `base..base + code.len()` straight from `code`
+    /// instead of RAM, which data accesses still see. Pass an `Rc` to share
+    /// one code image between several machines without copying it.
+    pub fn set_code_slice<C: Into<Rc<[u8]>>>(&mut self, base: u16, code: C) {
+        self.code = Some((base as usize, code.into()));
+    }
+
+    fn peek_code_8(&self, address: usize) -> u8 {
+        if let Some((base, code)) = &self.code {
+            if let Some(&byte) = address.checked_sub(*base).and_then(|i| code.get(i)) {
+                return byte;
+            }
+        }
+        self.ram.read(address % self.ram.size())
+    }
+
+    fn peek_code_16(&self, address: usize) -> u16 {
+        (self.peek_code_8(address) as u16) << 8 |
+            self.peek_code_8((address + 1) % self.ram.size()) as u16
+    }
+
+    /// The byte at `addr` as an instruction fetch would see it.
+    fn code_byte_at(&self, addr: usize) -> u8 {
+        self.peek_code_8(self.translate(Self::CODE_SEGMENT, addr))
+    }
+
+    fn read_16(&self, address: usize) -> Result<u16, EmuError> {
+        self.read_16_in(Self::DATA_SEGMENT, address)
+    }
+
+    fn read_16_in(&self, segment: usize, address: usize) -> Result<u16, EmuError> {
+        let address = self.translate(segment, address);
+        self.check_alignment(address)?;
+        self.check_mapped(address, 2)?;
+        if !self.permits(address, PERM_R) {
+            return Err(EmuError::ReadFault { addr: address });
+        }
+        self.charge_memory_access()?;
+        self.wait(address);
+        let value = match address {
+            Self::IRQ_MASK_PORT => self.interrupts.mask,
+            Self::IRQ_PENDING_PORT => self.interrupts.pending,
+            Self::INPUT_PORT => self.input_read(),
+            _ => self.peek_16(address),
+        };
+        self.report_access(MemAccess { addr: address, is_write: false, value, width: 2 });
+        Ok(value)
+    }
+
+    /// Call `f` with every data read and write, including stack and MMIO
+    /// accesses but not instruction fetches. Off by default.
+    pub fn set_memory_hook(&mut self, f: MemoryHook) {
+        *self.memory_hook.get_mut() = Some(f);
+    }
+
+    /// Call `f` with the address of every instruction word `step` fetches,
+    /// adding the latency it returns to the cycle count. Enough to model an
+    /// instruction cache.
+    pub fn set_fetch_hook(&mut self, f: FetchHook) {
+        self.fetch_hook = Some(f);
+    }
+
+    /// Call `f` with `(reg, old, new)` for every register an instruction
+    /// changes, IP included. Writes that leave a register's value as it was
+    /// aren't reported. Off by default.
+    pub fn set_register_write_hook(&mut self, f: RegisterHook) {
+        self.register_hook = Some(f);
+    }
+
+    fn report_access(&self, access: MemAccess) {
+        if let Some(mut stats) = self.stats.get() {
+            if access.is_write {
+                stats.memory_writes += 1;
+            } else {
+                stats.memory_reads += 1;
+            }
+            self.stats.set(Some(stats));
+        }
+        if let Some(hook) = self.memory_hook.borrow_mut().as_mut() {
+            hook(access);
+        }
+    }
+
+    fn fetch_16(&self, address: usize) -> Result<u16, EmuError> {
+        let address = self.translate(Self::CODE_SEGMENT, address);
+        self.check_alignment(address)?;
+        self.check_mapped(address, 2)?;
+        if !self.permits(address, PERM_X) {
+            return Err(EmuError::ExecuteFault { addr: address });
+        }
+        Ok(self.peek_code_16(address))
+    }
+
+    fn write_16(&mut self, address: usize, value: u16) -> Result<(), EmuError> {
+        self.write_16_in(Self::DATA_SEGMENT, address, value)
+    }
+
+    fn write_16_in(&mut self, segment: usize, address: usize, value: u16)
+            -> Result<(), EmuError> {
+        let address = self.translate(segment, address);
+        self.check_alignment(address)?;
+        self.check_mapped(address, 2)?;
+        if !self.permits(address, PERM_W) {
+            return Err(EmuError::WriteFault { addr: address });
+        }
+        self.charge_memory_access()?;
+        self.wait(address);
+        self.kick_watchdog(address);
+        self.report_access(MemAccess { addr: address, is_write: true, value, width: 2 });
+        if !self.port_write(address, value) {
+            self.ram.write(address, (value >> 8) as u8);
+            self.ram.write((address + 1) % self.ram.size(), value as u8);
+        }
+        Ok(())
+    }
+
+    /// Byte stores reach the output ports too, with the byte as the value.
+    fn write_8(&mut self, address: usize, value: u8) -> Result<(), EmuError> {
+        let address = self.translate(Self::DATA_SEGMENT, address);
+        self.check_mapped(address, 1)?;
+        if !self.permits(address, PERM_W) {
+            return Err(EmuError::WriteFault { addr: address });
+        }
+        self.charge_memory_access()?;
+        self.wait(address);
+        self.kick_watchdog(address);
+        let value = value as u16;
+        self.report_access(MemAccess { addr: address, is_write: true, value, width: 1 });
+        if !self.port_write(address, value) {
+            self.ram.write(address, value as u8);
+        }
+        Ok(())
+    }
+
+    /// Handle a write to `address` if it's a port, returning false if it's
+    /// ordinary memory.
+    fn port_write(&mut self, address: usize, value: u16) -> bool {
+        match address {
+            Self::CONSOLE_PORT => self.console_write(value),
+            Self::HEX_PORT => {
+                let _ = writeln!(self.output, "{:#06x}", value);
+            }
+            Self::DECIMAL_PORT => {
+                let _ = writeln!(self.output, "{}", value);
+            }
+            Self::CHAR_PORT => {
+                let _ = self.output.write_all(&[value as u8]);
+            }
+            Self::IRQ_MASK_PORT => self.interrupts.mask = value,
+            Self::IRQ_PENDING_PORT => self.interrupts.pending |= value,
+            Self::IRQ_ACK_PORT => self.interrupts.acknowledge(value as u8),
+            _ => return false,
+        }
+        true
+    }
+
+    fn push(&mut self, value: u16) -> Result<(), EmuError> {
+        self.regfile[Self::STACK_POINTER] =
+            self.regfile[Self::STACK_POINTER].wrapping_sub(2);
+        let sp = self.regfile[Self::STACK_POINTER] as usize;
+        self.write_16_in(Self::STACK_SEGMENT, sp, value)
+    }
+
+    fn pop(&mut self) -> Result<u16, EmuError> {
+        let sp = self.regfile[Self::STACK_POINTER] as usize;
+        let value = self.read_16_in(Self::STACK_SEGMENT, sp)?;
+        self.regfile[Self::STACK_POINTER] =
+            self.regfile[Self::STACK_POINTER].wrapping_add(2);
+        Ok(value)
+    }
+
+    fn should_jump(&self, cond: usize) -> bool {
+        condition_holds(cond, self.flags())
+    }
+
+    /// Scan the instructions in `range` for relative jumps and calls whose
+    /// target lands outside of it. The displacement is only 8 bits, so a
+    /// target that didn't fit usually shows up as a jump out of the program.
+    pub fn check_relative_jumps(&self, range: Range<usize>) -> Vec<RangeError> {
+        let mut errors = Vec::new();
+        let mut addr = range.start;
+        while addr < range.end {
+            let instruction = self.decode_at(addr);
+            match instruction {
+                Instruction::JumpRelative { offset, .. } |
+                Instruction::CallRelative { offset, .. } |
+                Instruction::JumpRegister { offset, .. } => {
+                    let target = (addr as u16)
+                        .wrapping_add(2)
+                        .wrapping_add(offset as i16 as u16);
+                    if !range.contains(&(target as usize)) {
+                        errors.push(RangeError { addr, target: target as usize });
+                    }
+                }
+                Instruction::Jump { target: JumpTarget::Relative(offset), .. } |
+                Instruction::Call { target: JumpTarget::Relative(offset), .. } |
+                Instruction::CompareJump { offset, .. } => {
+                    let target = (addr as u16)
+                        .wrapping_add(4)
+                        .wrapping_add(offset as u16);
+                    if !range.contains(&(target as usize)) {
+                        errors.push(RangeError { addr, target: target as usize });
+                    }
+                }
+                _ => {}
+            }
+            addr += instruction.size();
+        }
+        errors
+    }
+
+    /// Statically check `program`, as loaded at 0, for undefined opcodes,
+    /// branches out of it or into the middle of an instruction, and a
+    /// missing halt. The zero word counts as a halt.
+    pub fn verify_program(program: &[u8]) -> Vec<Diagnostic> {
+        let mut instructions = Vec::new();
+        let mut truncated = None;
+        let mut addr = 0;
+        for result in decode_all(program) {
+            let instruction = match result {
+                Ok(instruction) => instruction,
+                Err(DecodeError::UndefinedOpcode { word, .. }) => Instruction::Illegal(word),
+                Err(DecodeError::Truncated { addr }) => {
+                    truncated = Some(addr);
+                    break;
+                }
+            };
+            instructions.push((addr, instruction));
+            addr += instruction.size();
+        }
+
+        let mut diagnostics = Vec::new();
+        let mut halts = false;
+        for &(addr, instruction) in &instructions {
+            match instruction {
+                Instruction::Illegal(word) =>
+                    diagnostics.push(Diagnostic::UndefinedOpcode { addr, word }),
+                Instruction::Zero | Instruction::Halt | Instruction::HaltWithCode { .. } =>
+                    halts = true,
+                _ => {}
+            }
+            let target = match instruction.branch_target(addr as u16) {
+                Some(target) => target as usize,
+                None => continue,
+            };
+            if target >= program.len() {
+                if instruction.branch_offset().is_some() {
+                    diagnostics.push(Diagnostic::OutOfRange(RangeError { addr, target }));
+                }
+            } else if instructions.binary_search_by_key(&target, |&(a, _)| a).is_err() {
+                diagnostics.push(Diagnostic::MidInstruction { addr, target });
+            }
+        }
+        if let Some(addr) = truncated {
+            diagnostics.push(Diagnostic::Truncated { addr });
+        }
+        if !halts {
+            diagnostics.push(Diagnostic::MissingHalt);
+        }
+        diagnostics
+    }
+
+    /// Decode the instruction at `addr` as `step` would fetch it, through
+    /// the code segment.
+    fn decode_at(&self, addr: usize) -> Instruction {
+        let word_at = |addr: usize| {
+            let address = self.translate(Self::CODE_SEGMENT, addr);
+            if address + 1 < self.ram.size() {
+                self.peek_code_16(address)
+            } else {
+                0
+            }
+        };
+        Instruction::decode_wide(word_at(addr), word_at(addr + 2), word_at(addr + 4))
+    }
+
+    /// Decode the instruction at `addr` as `step` would in the current mode,
+    /// with its size in bytes.
+    fn decode_next(&self, addr: usize) -> (Instruction, usize) {
+        if self.compressed {
+            (Instruction::decode_compressed(self.code_byte_at(addr)), 1)
+        } else {
+            let instruction = self.decode_at(addr);
+            (instruction, instruction.size())
+        }
+    }
+
+    /// Lazily decode consecutive instructions from `start`, stopping at a
+    /// zero word or the end of RAM.
+    pub fn instructions(&self, start: usize)
+            -> impl Iterator<Item = (usize, Instruction)> + '_ {
+        let mut addr = start;
+        std::iter::from_fn(move || {
+            if addr + 1 >= self.ram.size() {
+                return None;
+            }
+            let instruction = self.decode_at(addr);
+            if instruction == Instruction::Zero {
+                return None;
+            }
+            let here = addr;
+            addr += instruction.size();
+            Some((here, instruction))
+        })
+    }
+
+    /// Disassemble the instructions in `range`, with branch targets shown
+    /// as absolute addresses and labelled from the symbol table.
+    pub fn disassemble_range(&self, range: Range<usize>) -> Vec<(usize, String)> {
+        let mut lines = Vec::new();
+        let mut addr = range.start;
+        while addr < range.end && addr + 1 < self.ram.size() {
+            let instruction = self.decode_at(addr);
+            lines.push((addr, instruction.display_at(addr as u16, &self.symbols)));
+            addr += instruction.size();
+        }
+        lines
+    }
+
+    /// Write an assembler-style listing of `start..end` to `path`: each
+    /// instruction's address, raw bytes and disassembly.
+    pub fn write_listing<P: AsRef<Path>>(&self, path: P, start: usize, end: usize)
+            -> io::Result<()> {
+        use std::fmt::Write;
+        let mut listing = String::new();
+        let mut addr = start;
+        while addr < end && addr + 1 < self.ram.size() {
+            for name in self.symbols.names_at(addr as u16) {
+                writeln!(listing, "{}:", name).unwrap();
+            }
+            let instruction = self.decode_at(addr);
+            let bytes: Vec<String> = (addr..addr + instruction.size())
+                .map(|a| format!("{:02x}", self.code_byte_at(a)))
+                .collect();
+            writeln!(listing, "{:04x}  {:<11}  {}", addr, bytes.join(" "), instruction)
+                .unwrap();
+            addr += instruction.size();
+        }
+        std::fs::write(path, listing)
+    }
+
+    /// Read the instruction word at IP, plus the one or two following words
+    /// if the encoding takes an immediate (otherwise 0). Doesn't advance IP.
+    pub fn fetch(&self) -> Result<(u16, u16, u16), EmuError> {
+        if self.strict_alignment && !self.ip().is_multiple_of(2) {
+            return Err(EmuError::MisalignedFetch { ip: self.ip() as u16 });
+        }
+        let word = self.fetch_16(self.ip())?;
+        let size = Instruction::decode(word, 0).size();
+        let mut following = [0; 2];
+        for (i, slot) in following.iter_mut().enumerate().take(size / 2 - 1) {
+            *slot = self.fetch_16(self.ip().wrapping_add(2 * (i + 1)))?;
+        }
+        Ok((word, following[0], following[1]))
+    }
+
+    /// Decode the words from `fetch`.
+    pub fn decode(&self, word: u16, next: u16, next2: u16) -> Instruction {
+        Instruction::decode_wide(word, next, next2)
+    }
+
+    /// Once the machine has halted, further steps do nothing and return
+    /// `Ok(false)` until a new program is loaded or IP is moved.
+    pub fn step(&mut self) -> Result<bool, EmuError> {
+        if self.halted {
+            return Ok(false);
+        }
+        self.deliver_interrupt()?;
+        self.exit_code = None;
+        let running = if self.compressed {
+            self.step_compressed()?
+        } else {
+            let (word, next, next2) = self.fetch()?;
+            if let Some(hook) = &mut self.fetch_hook {
+                let ip = self.regfile[Self::INSTRUCTION_POINTER];
+                for offset in (0..Instruction::decode(word, 0).size()).step_by(2) {
+                    self.cycles += hook(ip.wrapping_add(offset as u16));
+                }
+            }
+            self.run_word(word, next, next2)?
+        };
+        if running {
+            self.check_watchdog()?;
+        }
+        self.halted = !running;
+        if self.halted {
+            if let Some(mut callback) = self.halt_callback.take() {
+                callback(self);
+                self.halt_callback = Some(callback);
+            }
+        }
+        if running && self.sanity_checks {
+            self.check_invariants()?;
+        }
+        Ok(running)
+    }
+
+    /// Whether `step` is decoding one-byte compressed instructions, as
+    /// switched by `cmode`.
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    fn step_compressed(&mut self) -> Result<bool, EmuError> {
+        let ip = self.ip();
+        let address = self.translate(Self::CODE_SEGMENT, ip);
+        self.check_mapped(address, 1)?;
+        if !self.permits(address, PERM_X) {
+            return Err(EmuError::ExecuteFault { addr: address });
+        }
+        let byte = self.peek_code_8(address);
+        if let Some(hook) = &mut self.fetch_hook {
+            self.cycles += hook(ip as u16);
+        }
+        let instruction = Instruction::decode_compressed(byte);
+        trace!(self, "{:>2}: {:0>8b}  {}", ip, byte, instruction);
+        // Recorded as the full-width equivalent, so the profile and
+        // `last_instruction` decode as usual.
+        self.run_instruction(instruction, instruction.encode()[0], 1)
+    }
+
+    /// Like `step`, but describe what was run and what it changed.
+    pub fn step_detailed(&mut self) -> Result<StepInfo, EmuError> {
+        let ip = self.ip() as u16;
+        let before = self.regfile;
+        self.executed = None;
+        let running = self.step()?;
+        // An interrupt may have sent the step somewhere else.
+        let (addr, instruction) = self.executed
+            .unwrap_or_else(|| (ip, self.decode_next(ip as usize).0));
+        let changed = before.iter().zip(self.regfile.iter()).enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(reg, (_, &new))| (reg, new))
+            .collect();
+        Ok(StepInfo { addr, instruction, changed, halted: !running })
+    }
+
+    /// Up to `n` `step_detailed` results, stopping after a halt or error.
+    pub fn step_n(&mut self, n: usize) -> Vec<Result<StepInfo, EmuError>> {
+        let mut steps = Vec::new();
+        while steps.len() < n && !self.halted {
+            let step = self.step_detailed();
+            let done = step.as_ref().map_or(true, |info| info.halted);
+            steps.push(step);
+            if done {
+                break;
+            }
+        }
+        steps
+    }
+
+    /// Run up to and including the next control-flow instruction, whether
+    /// or not its branch is taken, or until the machine halts.
+    pub fn step_block(&mut self) -> Result<BlockInfo, EmuError> {
+        let mut instructions = Vec::new();
+        loop {
+            let addr = self.ip() as u16;
+            let (instruction, size) = self.decode_next(addr as usize);
+            let running = self.step()?;
+            instructions.push((addr, instruction));
+            let fell_through = self.ip() == addr as usize + size;
+            if !running || instruction.is_control_flow() || !fell_through {
+                return Ok(BlockInfo { instructions, exit: (addr, self.ip() as u16) });
+            }
+        }
+    }
+
+    /// Expect the guest to write to `kick` at least every `timeout` cycles,
+    /// taking `action` if it doesn't.
+    pub fn set_watchdog(&mut self, kick: u16, timeout: u64, action: WatchdogAction) {
+        self.watchdog = Some(Watchdog {
+            kick: kick as usize,
+            timeout,
+            action,
+            last_kick: self.cycles,
+        });
+    }
+
+    fn kick_watchdog(&mut self, address: usize) {
+        if let Some(watchdog) = &mut self.watchdog {
+            if watchdog.kick == address {
+                watchdog.last_kick = self.cycles;
+            }
+        }
+    }
+
+    fn check_watchdog(&mut self) -> Result<(), EmuError> {
+        let watchdog = match &mut self.watchdog {
+            Some(watchdog) if self.cycles.saturating_sub(watchdog.last_kick) > watchdog.timeout =>
+                watchdog,
+            _ => return Ok(()),
+        };
+        match watchdog.action {
+            WatchdogAction::Fault => Err(EmuError::WatchdogExpired),
+            WatchdogAction::Reset => {
+                self.restart();
+                Ok(())
+            }
+        }
+    }
+
+    /// The code given by the `hlt rD` that halted the machine, or `None` if
+    /// it's running or halted some other way.
+    pub fn exit_code(&self) -> Option<u16> {
+        self.exit_code.filter(|_| self.halted)
+    }
+
+    pub fn raise_irq(&mut self, line: u8) {
+        self.interrupts.raise(line);
+    }
+
+    pub fn interrupt_controller(&self) -> &InterruptController {
+        &self.interrupts
+    }
+
+    fn deliver_interrupt(&mut self) -> Result<(), EmuError> {
+        if let Some(line) = self.interrupts.next() {
+            trace!(self, "irq {}", line);
+            self.interrupts.deliver(line);
+            let handler = self.peek_16(Self::IRQ_VECTORS + 2 * line as usize);
+            self.push(self.regfile[Self::INSTRUCTION_POINTER])?;
+            self.regfile[Self::INSTRUCTION_POINTER] = handler;
+        }
+        Ok(())
+    }
+
+    /// After every step, check that SP is inside the stack region (if one
+    /// is set) and that IP points at a decodable instruction, failing with
+    /// `EmuError::InvariantViolation` otherwise. For debugging hand-written
+    /// assembly.
+    pub fn set_sanity_checks(&mut self, enabled: bool) {
+        self.sanity_checks = enabled;
+    }
+
+    /// The addresses the stack may occupy. An empty stack has SP at the
+    /// end of the range.
+    pub fn set_stack_region(&mut self, region: Range<usize>) {
+        self.stack_region = Some(region);
+    }
+
+    fn check_invariants(&self) -> Result<(), EmuError> {
+        let sp = self.regfile[Self::STACK_POINTER];
+        if let Some(region) = &self.stack_region {
+            let empty = sp as usize == region.end & 0xFFFF;
+            if !region.contains(&(sp as usize)) && !empty {
+                return Err(EmuError::InvariantViolation(
+                    Invariant::StackPointerOutOfRange { sp }));
+            }
+        }
+        if let (Instruction::Illegal(word), _) = self.decode_next(self.ip()) {
+            return Err(EmuError::InvariantViolation(
+                Invariant::UndecodableInstruction { addr: self.ip(), word }));
+        }
+        Ok(())
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Execute `word` as if it had been fetched from the current IP, with
+    /// `next` as the following word for encodings that take an immediate.
+    /// IP advances as normal.
+    pub fn execute_word(&mut self, word: u16, next: u16) -> Result<(), EmuError> {
+        self.run_word(word, next, 0)?;
+        Ok(())
+    }
+
+    fn run_word(&mut self, word: u16, next: u16, next2: u16) -> Result<bool, EmuError> {
+        trace!(self, "{:>2}: {:0>16b}  {}", self.ip(), word, self.dump_registers());
+        let instruction = Instruction::decode_wide(word, next, next2);
+        self.run_instruction(instruction, word, instruction.size())
+    }
+
+    /// Execute `instruction`, fetched as `size` bytes starting with `word`,
+    /// and do the tracing and bookkeeping that follows.
+    fn run_instruction(&mut self, instruction: Instruction, word: u16, size: usize)
+            -> Result<bool, EmuError> {
+        self.executed = Some((self.ip() as u16, instruction));
+        if instruction != Instruction::Zero {
+            self.last_instruction = Some((self.ip() as u16, word));
+            if let Some(profile) = &mut self.profile {
+                *profile.entry(word).or_insert(0) += 1;
+            }
+        }
+        let (cycles, ip, before) = (self.cycles, self.ip(), self.regfile);
+        let running = self.execute(instruction)?;
+        // Every compressed form falls through, so skip just the one byte.
+        if running && size != instruction.size() {
+            self.regfile[Self::INSTRUCTION_POINTER] = (ip as u16).wrapping_add(size as u16);
+        }
+        if self.trace_writer.is_some() {
+            self.write_trace_line(cycles, ip, instruction, &before);
+        }
+        if let Some(hook) = &mut self.register_hook {
+            for (reg, (&old, &new)) in before.iter().zip(self.regfile.iter()).enumerate() {
+                if old != new {
+                    hook(reg, old, new);
+                }
+            }
+        }
+        let fallthrough = ip + size;
+        self.last_branch = if instruction.is_control_flow() {
+            Some(BranchInfo {
+                addr: ip as u16,
+                target: self.ip() as u16,
+                taken: self.ip() != fallthrough & 0xFFFF,
+            })
+        } else {
+            None
+        };
+        if let (Some(mut stats), Some(branch)) = (self.stats.get(), self.last_branch) {
+            if branch.taken {
+                stats.branches_taken += 1;
+                match instruction {
+                    Instruction::Call { .. } | Instruction::CallRelative { .. } => stats.calls += 1,
+                    Instruction::Ret => stats.returns += 1,
+                    _ => {}
+                }
+            } else {
+                stats.branches_not_taken += 1;
+            }
+            self.stats.set(Some(stats));
+        }
+        if self.detect_ip_wrap && fallthrough > 0xFFFF && self.ip() == fallthrough & 0xFFFF {
+            return Err(EmuError::InstructionPointerWrap { addr: ip });
+        }
+        Ok(running)
+    }
+
+    /// Fault with `EmuError::InstructionPointerWrap` when execution falls
+    /// off the end of the address space instead of wrapping around to 0.
+    pub fn set_ip_wrap_detection(&mut self, detect: bool) {
+        self.detect_ip_wrap = detect;
+    }
+
+    /// Append a line per executed instruction to the file at `path`, with
+    /// the cycle count, IP, the instruction and the registers it changed.
+    /// The log is stable across runs, so it can be diffed for regressions.
+    pub fn trace_to_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        self.trace_writer = Some(Box::new(file));
+        Ok(())
+    }
+
+    fn write_trace_line(&mut self, cycles: u64, ip: usize, instruction: Instruction,
+            before: &[u16; Self::REGISTER_COUNT]) {
+        use std::fmt::Write;
+        let mut line = format!("{:>8} {:04x}  {:<24}", cycles, ip, instruction.to_string());
+        for (reg, (old, new)) in before.iter().zip(self.regfile.iter()).enumerate() {
+            if old != new {
+                match reg {
+                    Self::INSTRUCTION_POINTER => write!(line, " ip={:04x}", new),
+                    Self::FLAG_REGISTER => write!(line, " flags={:04x}", new),
+                    _ => write!(line, " r{}={}", reg, self.format_register(*new)),
+                }.unwrap();
+            }
+        }
+        // Like console output, tracing is best-effort.
+        if let Some(writer) = &mut self.trace_writer {
+            let _ = writeln!(writer, "{}", line.trim_end());
+        }
+    }
+
+    fn jump_target(&self, target: JumpTarget) -> Result<u16, EmuError> {
+        Ok(match target {
+            JumpTarget::Register(rd) => self.regfile[rd],
+            JumpTarget::Indirect(rd) => self.read_16(self.regfile[rd] as usize)?,
+            JumpTarget::Immediate(n) => n,
+            JumpTarget::Relative(n) => self.regfile[Self::INSTRUCTION_POINTER]
+                .wrapping_add(4)
+                .wrapping_add(n as u16),
+            JumpTarget::DoubleIndirect(rd) => {
+                let pointer = self.read_16(self.regfile[rd] as usize)?;
+                self.read_16(pointer as usize)?
+            }
+        })
+    }
+
+    /// Carry out a decoded instruction, advancing IP past it (or to its
+    /// jump target). Returns false when the machine halts.
+    pub fn execute(&mut self, instruction: Instruction) -> Result<bool, EmuError> {
+        if let Some(written) = self.written_registers {
+            let (reads, writes) = instruction.register_effects();
+            if let Some(&reg) = reads.iter().find(|&&reg| written & 1 << reg == 0) {
+                return Err(EmuError::UninitializedRegister { reg });
+            }
+            self.written_registers = Some(writes.iter().fold(written, |w, reg| w | 1 << reg));
+        }
+        let running = self.execute_unchecked(instruction);
+        let wait = self.pending_wait.take();
+        if running? {
+            self.cycles += 1 + wait;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Instructions executed so far, at one cycle each, plus any latency
+    /// charged by the fetch hook and memory wait states.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn execute_unchecked(&mut self, instruction: Instruction) -> Result<bool, EmuError> {
+        use Instruction::*;
+        match instruction {
+            Zero => match self.halt_policy {
+                HaltPolicy::HaltOnZero => return Ok(false),
+                HaltPolicy::TreatZeroAsNop => {}
+                HaltPolicy::RequireExplicitHalt => {
+                    return Err(EmuError::IllegalInstruction {
+                        addr: self.ip(),
+                        word: 0,
+                    });
+                }
+            },
+            Not { rd } => self.regfile[rd] = !self.regfile[rd],
+            Neg { rd } => self.regfile[rd] = !self.regfile[rd].wrapping_add(1),
+            Push { rd } => self.push(self.regfile[rd])?,
+            Pop { rd } => self.regfile[rd] = self.pop()?,
+            Inc { rd } => self.regfile[rd] = self.regfile[rd].wrapping_add(1),
+            Dec { rd } => self.regfile[rd] = self.regfile[rd].wrapping_sub(1),
+            LoadFlags { rd } => self.regfile[rd] = self.flags().bits(),
+            StoreFlags { rd } => {
+                let flags = alu::Flags::from_bits_truncate(self.regfile[rd]);
+                self.regfile[Self::FLAG_REGISTER] = flags.bits();
+            }
+            Abs { rd } | SNeg { rd } => {
+                let (result, flags) = match instruction {
+                    Abs { .. } => alu::abs(self.regfile[rd]),
+                    _ => alu::negate(self.regfile[rd]),
+                };
+                self.regfile[Self::FLAG_REGISTER] = flags.bits();
+                self.regfile[rd] = result;
+            }
+            Alu { op, rd, rs } => {
+                let va = self.regfile[rd];
+                let vb = self.regfile[rs];
+                let (result, flags) = alu::alu(op, va, vb, self.flags());
+                if flags.contains(alu::EF) {
+                    return Err(EmuError::IllegalAluOp { addr: self.ip(), op });
+                }
+                self.regfile[Self::FLAG_REGISTER] = flags.bits();
+                self.regfile[rd] = result;
+            }
+            Jump { cond, target } => {
+                let target = self.jump_target(target)?;
+                self.advance_ip(instruction.size());
+                if self.should_jump(cond) {
+                    trace!(self, "jmp {}", target as i16);
+                    self.regfile[Self::INSTRUCTION_POINTER] = target;
+                }
+                return Ok(true);
+            }
+            AluImmediate { op, rd, n } => {
+                let n = match self.alu_immediate_mode {
+                    ImmediateMode::ZeroExtend => n,
+                    ImmediateMode::SignExtend => ((n << 12) as i16 >> 12) as u16,
+                };
+                let va = self.regfile[rd];
+                let (result, flags) = alu::alu(op, va, n, self.flags());
+                if flags.contains(alu::EF) {
+                    return Err(EmuError::IllegalAluOp { addr: self.ip(), op });
+                }
+                self.regfile[Self::FLAG_REGISTER] = flags.bits();
+                self.regfile[rd] = result;
+            }
+            JumpRelative { cond, offset } => {
+                // The offset is relative to the end of the jump, not its
+                // start, so `jmp 0` falls through to the next instruction.
+                self.advance_ip(2);
+                if self.should_jump(cond) {
+                    trace!(self, "jmp relative {}", offset);
+                    self.advance_ip(offset as i16 as usize);
+                }
+                return Ok(true);
+            }
+            Load { rd, rs, ro } => {
+                let address = self.regfile[rs].wrapping_add(self.regfile[ro]);
+                self.regfile[rd] = self.read_16(address as usize)?;
+            }
+            Store { rd, rs, ro } => {
+                let address = self.regfile[rd].wrapping_add(self.regfile[ro]);
+                self.write_16(address as usize, self.regfile[rs])?;
+            }
+            StoreByte { rd, rs } => {
+                self.write_8(self.regfile[rd] as usize, self.regfile[rs] as u8)?;
+            }
+            CompareImmediate { rd, n } => {
+                let (_, flags) = alu::alu(11, self.regfile[rd], n, self.flags());
+                self.regfile[Self::FLAG_REGISTER] = flags.bits();
+            }
+            LoadDisplaced { rd, rs, disp } => {
+                let address = self.regfile[rs].wrapping_add(disp as i16 as u16);
+                self.regfile[rd] = self.read_16(address as usize)?;
+            }
+            Call { cond, target } => {
+                let target = self.jump_target(target)?;
+                self.advance_ip(instruction.size());
+                if self.should_jump(cond) {
+                    trace!(self, "call {}", target as i16);
+                    self.push(self.regfile[Self::INSTRUCTION_POINTER])?;
+                    self.regfile[Self::INSTRUCTION_POINTER] = target;
+                }
+                return Ok(true);
+            }
+            CallRelative { cond, offset } => {
+                self.advance_ip(2);
+                if self.should_jump(cond) {
+                    trace!(self, "call relative {}", offset);
+                    self.push(self.regfile[Self::INSTRUCTION_POINTER])?;
+                    self.advance_ip(offset as i16 as usize);
+                }
+                return Ok(true);
+            }
+            MovI8 { rd, n } => self.regfile[rd] = match self.mov_immediate_mode {
+                ImmediateMode::ZeroExtend => n as u16,
+                ImmediateMode::SignExtend => n as i8 as u16,
+            },
+            MovI16 { rd, n } => self.regfile[rd] = n,
+            Cas { ra, re, rn } => {
+                let address = self.regfile[ra] as usize;
+                let current = self.read_16(address)?;
+                let (_, flags) = alu::alu(11, current, self.regfile[re], self.flags());
+                self.regfile[Self::FLAG_REGISTER] = flags.bits();
+                if flags.contains(alu::ZF) {
+                    self.write_16(address, self.regfile[rn])?;
+                }
+            }
+            JumpRegister { rd, nonzero, offset } => {
+                self.advance_ip(2);
+                if (self.regfile[rd] != 0) == nonzero {
+                    self.advance_ip(offset as i16 as usize);
+                }
+                return Ok(true);
+            }
+            FixedMul { rd, rs, signed } => {
+                let product = if signed {
+                    (self.regfile[rd] as i16 as i32 * self.regfile[rs] as i16 as i32) as u32
+                } else {
+                    self.regfile[rd] as u32 * self.regfile[rs] as u32
+                };
+                self.set_acc32(product);
+                self.regfile[rd] = (product >> 8) as u16;
+            }
+            ReadCycles { rd } => self.regfile[rd] = self.cycles as u16,
+            CompareJump { cond, ra, rb, offset } => {
+                let (_, flags) = alu::alu(11, self.regfile[ra], self.regfile[rb], self.flags());
+                self.advance_ip(4);
+                if condition_holds(cond, flags) {
+                    self.advance_ip(offset as usize);
+                }
+                return Ok(true);
+            }
+            LoadAcc32 { n } => self.set_acc32(n),
+            PushMultiple { mask } => {
+                for reg in mask_registers(mask) {
+                    self.push(self.regfile[reg])?;
+                }
+            }
+            PopMultiple { mask } => {
+                for reg in mask_registers(mask).into_iter().rev() {
+                    self.regfile[reg] = self.pop()?;
+                }
+            }
+            Nop => {}
+            Compressed(on) => self.compressed = on,
+            Halt => return Ok(false),
+            HaltWithCode { rd } => {
+                self.exit_code = Some(self.regfile[rd]);
+                return Ok(false);
+            }
+            Ret => {
+                self.regfile[Self::INSTRUCTION_POINTER] = self.pop()?;
+                return Ok(true);
+            }
+            Mov { rd, rs } => {
+                trace!(self, "mov r{}, r{}", rd, rs);
+                self.regfile[rd] = self.regfile[rs];
+                // Writing IP this way is an unconditional register jump:
+                // execution continues exactly at the value moved in.
+                if rd == Self::INSTRUCTION_POINTER {
+                    return Ok(true);
+                }
+            }
+            Illegal(word) => {
+                let addr = self.ip();
+                if let Some(mut handler) = self.illegal_handler.take() {
+                    let handled = handler(self, word);
+                    self.illegal_handler.get_or_insert(handler);
+                    if handled {
+                        if self.ip() == addr {
+                            self.advance_ip(2);
+                        }
+                        return Ok(true);
+                    }
+                }
+                return Err(EmuError::IllegalInstruction { addr, word });
+            }
+        }
+        self.advance_ip(instruction.size());
+        Ok(true)
+    }
+
+    /// Give illegal instruction words to `f` instead of faulting, to extend
+    /// the instruction set. IP points at the word when `f` is called; if
+    /// it returns true, execution carries on after the word (or wherever
+    /// `f` moved IP to), and if false the machine faults as usual.
+    pub fn set_illegal_handler(&mut self, f: IllegalHandler) {
+        self.illegal_handler = Some(f);
+    }
+
+    /// Call `f` when `step` halts the machine, for flushing output or
+    /// printing a summary. Stepping a halted machine doesn't call it again.
+    pub fn set_halt_callback(&mut self, f: HaltCallback) {
+        self.halt_callback = Some(f);
+    }
+
+    /// The address and opcode of the most recently executed instruction.
+    pub fn last_instruction(&self) -> Option<(u16, u16)> {
+        self.last_instruction
+    }
+
+    /// The last instruction executed if it was a jump, call or `ret`, and
+    /// whether it was taken.
+    pub fn last_was_branch(&self) -> Option<BranchInfo> {
+        self.last_branch
+    }
+
+    /// Start or stop counting executed opcodes. Stopping discards the counts.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profile = if enabled { Some(HashMap::new()) } else { None };
+    }
+
+    /// Execution counts per full opcode word.
+    pub fn opcode_histogram(&self) -> HashMap<u16, u64> {
+        self.profile.clone().unwrap_or_default()
+    }
+
+    /// Execution counts per opcode class (the top nibble).
+    pub fn opcode_class_histogram(&self) -> HashMap<u16, u64> {
+        let mut classes = HashMap::new();
+        for (word, count) in self.profile.iter().flatten() {
+            *classes.entry(word >> 12).or_insert(0) += count;
+        }
+        classes
+    }
+
+    /// Compare registers and memory against `other`.
+    pub fn diff_state(&self, other: &Simple) -> StateDiff {
+        let mut diff = StateDiff::default();
+        for (reg, (&ours, &theirs)) in
+                self.regfile.iter().zip(other.regfile.iter()).enumerate() {
+            if ours != theirs {
+                diff.registers.push((reg, ours, theirs));
+            }
+        }
+        let len = self.ram.size().max(other.ram.size());
+        let mut run_start = None;
+        for addr in 0..=len {
+            let byte = |simple: &Simple| {
+                if addr < simple.ram.size() { simple.ram.read(addr) } else { 0 }
+            };
+            let (ours, theirs) = (byte(self), byte(other));
+            match (run_start, addr < len && ours != theirs) {
+                (None, true) => run_start = Some(addr),
+                (Some(start), false) => {
+                    diff.memory.push(start..addr);
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        diff
+    }
+
+    /// Describe `err` along with the faulting instruction, the registers and
+    /// the top of the stack.
+    pub fn crash_report(&self, err: &EmuError) -> String {
+        use std::fmt::Write;
+        let addr = match *err {
+            EmuError::IllegalInstruction { addr, .. } |
+            EmuError::IllegalAluOp { addr, .. } => addr,
+            _ => self.last_instruction
+                .map(|(addr, _)| addr as usize)
+                .unwrap_or_else(|| self.ip()),
+        };
+        let instruction = self.decode_at(addr);
+        let mut report = String::new();
+        writeln!(report, "error: {}", err).unwrap();
+        write!(report, "at {:#06x}:", addr).unwrap();
+        for offset in (0..instruction.size()).step_by(2) {
+            let bytes = [self.code_byte_at(addr + offset), self.code_byte_at(addr + offset + 1)];
+            write!(report, " {:04x}", u16::from_be_bytes(bytes)).unwrap();
+        }
+        writeln!(report, "  {}", instruction).unwrap();
+        for (i, chunk) in self.regfile[0..16].chunks(4).enumerate() {
+            for (j, value) in chunk.iter().enumerate() {
+                write!(report, "r{:<2} {:04x}  ", i * 4 + j, value).unwrap();
+            }
+            writeln!(report).unwrap();
+        }
+        writeln!(report, "ip  {:04x}  flags {:04x}", self.ip(), self.flags().bits()).unwrap();
+        let sp = self.regfile[Self::STACK_POINTER];
+        writeln!(report, "stack:").unwrap();
+        for i in 0..4 {
+            let address = sp.wrapping_add(i * 2);
+            let value = ((self.ram.read(address as usize) as u16) << 8) |
+                self.ram.read(address.wrapping_add(1) as usize) as u16;
+            writeln!(report, "  {:04x}: {:04x}", address, value).unwrap();
+        }
+        report
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    /// Stop `run` after any step that changes `reg` to `value`.
+    pub fn add_register_breakpoint(&mut self, reg: usize, value: u16) {
+        assert!(reg < Self::REGISTER_COUNT, "no register r{}", reg);
+        self.register_breakpoints.push((reg, value));
+    }
+
+    fn hit_register_breakpoint(&self, before: &[u16; Self::REGISTER_COUNT])
+            -> Option<(usize, u16)> {
+        self.register_breakpoints.iter()
+            .copied()
+            .find(|&(reg, value)| before[reg] != value && self.regfile[reg] == value)
+    }
+
+    /// Break at the address of symbol `name`. Returns false if there's no
+    /// such symbol.
+    pub fn add_symbol_breakpoint(&mut self, name: &str) -> bool {
+        match self.resolve_symbol(name) {
+            Some(addr) => {
+                self.add_breakpoint(Breakpoint::new(addr));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Labels for the loaded program, shown in listings and usable for
+    /// breakpoints.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    pub fn resolve_symbol(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name)
+    }
+
+    fn hit_breakpoint(&mut self) -> bool {
+        let ip = self.ip() as u16;
+        for i in 0..self.breakpoints.len() {
+            let breakpoint = &self.breakpoints[i];
+            if breakpoint.addr != ip {
+                continue;
+            }
+            if let Some(condition) = &breakpoint.condition {
+                if !condition(self) {
+                    continue;
+                }
+            }
+            if breakpoint.ignore_count > 0 {
+                self.breakpoints[i].ignore_count -= 1;
+                continue;
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Run until the machine halts, a breakpoint is hit or the step limit
+    /// runs out. Running again after a breakpoint resumes from it.
+    pub fn run(&mut self) -> StopReason {
+        self.run_limited(Some(self.step_limit), None)
+    }
+
+    /// Like `run`, but without the step limit, so it may never return.
+    pub fn run_unbounded(&mut self) -> StopReason {
+        self.run_limited(None, None)
+    }
+
+    fn run_limited(&mut self, limit: Option<u64>, cycle_budget: Option<u64>) -> StopReason {
+        let mut resuming = std::mem::replace(&mut self.stopped_at_breakpoint, false);
+        let (mut steps, start) = (0, self.cycles);
+        loop {
+            if limit == Some(steps) || cycle_budget.is_some_and(|b| self.cycles - start >= b) {
+                return StopReason::StepLimit;
+            }
+            steps += 1;
+            if !resuming && self.hit_breakpoint() {
+                self.stopped_at_breakpoint = true;
+                return StopReason::Breakpoint;
+            }
+            resuming = false;
+            let before = self.regfile;
+            match self.step() {
+                Ok(true) => if let Some((reg, value)) = self.hit_register_breakpoint(&before) {
+                    return StopReason::RegisterBreak { reg, value };
+                },
+                Ok(false) => return StopReason::Halted,
+                Err(EmuError::QuotaExceeded) => return StopReason::QuotaExceeded,
+                Err(EmuError::InstructionPointerWrap { .. }) =>
+                    return StopReason::InstructionPointerWrap,
+                Err(err) => return StopReason::Fault(err),
+            }
+        }
+    }
+
+    /// Like `run`, but count branches, calls, returns and memory accesses
+    /// along the way.
+    pub fn run_stats(&mut self) -> RunStats {
+        self.stats.set(Some(RunStats::default()));
+        let stop_reason = self.run();
+        let stats = self.stats.take().unwrap_or_default();
+        RunStats { stop_reason: Some(stop_reason), ..stats }
+    }
+
+    /// Run for at most `budget` cycles, for sharing time between several
+    /// machines. Call again to carry on where it left off. An instruction
+    /// that costs more than what's left of the budget still runs, and the
+    /// cycles it overran by come out of the next call's budget.
+    pub fn emulate(&mut self, budget: u64) -> Emulated {
+        let owed = self.overrun.min(budget);
+        self.overrun -= owed;
+        let available = budget - owed;
+        let start = self.cycles;
+        let stop_reason = self.run_limited(None, Some(available));
+        let ran = self.cycles - start;
+        self.overrun += ran.saturating_sub(available);
+        Emulated { cycles_used: owed + ran.min(available), stop_reason }
+    }
+
+    /// How many instructions would run before the machine halts, if it
+    /// does so within `max` steps without faulting. Runs a copy, leaving
+    /// this machine untouched.
+    pub fn estimate_until_halt(&self, max: usize) -> Option<usize> {
+        let mut copy = self.fork();
+        for count in 0..max {
+            match copy.step() {
+                Ok(true) => {}
+                Ok(false) => return Some(count),
+                Err(_) => return None,
+            }
+        }
+        None
+    }
+
+    /// A copy with the same registers, memory and execution settings, for
+    /// looking ahead. Output is discarded, and hooks, breakpoints and
+    /// tracing are left off.
+    fn fork(&self) -> Simple {
+        let ram: Vec<u8> = (0..self.ram.size()).map(|addr| self.ram.read(addr)).collect();
+        Simple {
+            regfile: self.regfile,
+            ram: Box::new(ram),
+            segmented: self.segmented,
+            strict_alignment: self.strict_alignment,
+            regions: self.regions.clone(),
+            mapped: self.mapped.clone(),
+            code: self.code.clone(),
+            halt_policy: self.halt_policy,
+            alu_immediate_mode: self.alu_immediate_mode,
+            mov_immediate_mode: self.mov_immediate_mode,
+            memory_accesses: self.memory_accesses.clone(),
+            wait_states: self.wait_states.clone(),
+            memory_quota: self.memory_quota,
+            trace: false,
+            written_registers: self.written_registers,
+            cycles: self.cycles,
+            halted: self.halted,
+            compressed: self.compressed,
+            signed_registers: self.signed_registers,
+            detect_ip_wrap: self.detect_ip_wrap,
+            sanity_checks: self.sanity_checks,
+            stack_region: self.stack_region.clone(),
+            interrupts: self.interrupts,
+            watchdog: self.watchdog,
+            console_mode: self.console_mode,
+            console_line: self.console_line.clone(),
+            console_radix: self.console_radix,
+            console_width: self.console_width,
+            output: Box::new(io::sink()),
+            ..Simple::new()
+        }
+    }
+
+    /// Step for as long as `pred` holds and the machine hasn't halted.
+    pub fn run_while<F: FnMut(&Simple) -> bool>(&mut self, mut pred: F)
+            -> Result<(), EmuError> {
+        while pred(self) && self.step()? {}
+        Ok(())
+    }
+}
+
+/// Shows the registers and flags, leaving out memory.
+impl fmt::Debug for Simple {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Simple {{")?;
+        for (reg, value) in self.regfile[..16].iter().enumerate() {
+            write!(f, " r{}={:04x}", reg, value)?;
+        }
+        write!(f, " sp={:04x} ip={:04x} flags={:?} }}",
+            self.regfile[Self::STACK_POINTER], self.ip(), self.flags())
+    }
+}
+
+impl Default for Simple {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn add_program() {
+        // mov r1, 10
+        // mov r2, 11
+        // add r2, r1
+        let program = vec![0x81,0x0a,0x82,0x0b,0x01,0x21];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[2], 21);
+    }
+
+    #[test]
+    fn registers_snapshot() {
+        let program = vec![0x81,0x0a,0x82,0x0b,0x01,0x21];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        let registers = s.registers();
+        assert_eq!(registers.r1, 10);
+        assert_eq!(registers.r2, 21);
+        assert_eq!(registers.sp, registers.r15);
+        assert_eq!(registers.ip, 6);
+        assert_eq!(registers.flags, alu::Flags::empty());
+        assert!(format!("{:#?}", registers).contains("r2: 21,"));
+    }
+
+    #[test]
+    fn save_restore_registers() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.load_program(assemble(&[
+            MovI8 { rd: 1, n: 0x40 },
+            MovI8 { rd: 2, n: 9 },
+            Store { rd: 1, rs: 2, ro: 0 },
+        ]));
+        let saved = s.save_registers();
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[2], 9);
+        s.restore_registers(saved);
+        assert_eq!(s.regfile, saved);
+        assert_eq!(s.ip(), 0);
+        assert_eq!(s.peek_16(0x40), 9);
+    }
+
+    #[test]
+    fn estimate_until_halt() {
+        let program = vec![0x81,0x0a,0x82,0x0b,0x01,0x21];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.estimate_until_halt(100), Some(3));
+        assert_eq!(s.estimate_until_halt(2), None);
+        assert_eq!(s.ip(), 0);
+        assert_eq!(s.regfile[2], 0);
+
+        s.load_program(vec![0x3d,0xfe]);
+        assert_eq!(s.estimate_until_halt(100), None);
+    }
+
+    #[test]
+    fn run_stop_reason() {
+        let program = vec![0x81,0x0a,0x82,0x0b,0x01,0x21];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[2], 21);
+    }
+
+    #[test]
+    fn jmp_program() {
+        // mov r1, 1
+        // jmp [ip + 2]
+        // mov r2, 2
+        let program = vec![0x81,0x01,0x3d,0x02,0x82,0x02];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 1);
+        assert_eq!(s.regfile[2], 0);
+    }
+
+    #[test]
+    fn relative_jump_base() {
+        // mov r1, 1
+        // jmp [ip + 4]
+        // mov r2, 2
+        // mov r3, 3
+        // mov r4, 4
+        let program = vec![0x81,0x01,0x3d,0x04,0x82,0x02,0x83,0x03,0x84,0x04];
+        let mut s = Simple::new();
+        s.load_program(program);
+        s.step().unwrap();
+        s.step().unwrap();
+        assert_eq!(s.ip(), 8);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[2], 0);
+        assert_eq!(s.regfile[3], 0);
+        assert_eq!(s.regfile[4], 4);
+    }
+
+    #[test]
+    fn fib_program() {
+        let mut s = Simple::new();
+        s.load_program(FIB_PROGRAM.to_vec());
+        s.regfile[1] = 11;
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 55);
+    }
+
+    #[test]
+    fn step_block_fib() {
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(FIB_PROGRAM.to_vec());
+        s.regfile[1] = 11;
+        let first = s.step_block().unwrap();
+        assert_eq!(first.instructions.len(), 2);
+        assert_eq!(first.exit, (0x02, 0x04));
+        let mut loop_blocks = 0;
+        while !s.is_halted() {
+            let block = s.step_block().unwrap();
+            if block.instructions[0].0 == 0x0c {
+                assert_eq!(block.instructions.len(), 3);
+                loop_blocks += 1; // each pass advances fib by two
+            }
+        }
+        assert_eq!(loop_blocks, 5);
+        assert_eq!(s.regfile[1], 55);
+    }
+
+    #[test]
+    fn debug_shows_registers() {
+        let mut s = Simple::new();
+        s.regfile[3] = 0xbeef;
+        s.regfile[Simple::FLAG_REGISTER] = (alu::ZF | alu::CF).bits();
+        let debug = format!("{:?}", s);
+        assert!(debug.contains("r3=beef"), "{}", debug);
+        assert!(debug.contains("r15=0000 sp=0000 ip=0000"), "{}", debug);
+        assert!(debug.contains("ZF | CF"), "{}", debug);
+        assert!(debug.len() < 200);
+    }
+
+    #[test]
+    fn byte_store_to_console() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        let output = SharedBuffer::default();
+        s.set_output(Box::new(output.clone()));
+        s.load_program(assemble(&[
+            MovI16 { rd: 1, n: Simple::CONSOLE_PORT as u16 },
+            MovI16 { rd: 2, n: 0x1241 },
+            StoreByte { rd: 1, rs: 2 },
+            Halt,
+        ]));
+        assert_eq!(s.decode_at(8).to_string(), "movb [r1], r2");
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(*output.0.borrow(), b"OUT: 65\n");
+        assert_eq!(s.peek_16(Simple::CONSOLE_PORT), 0);
+    }
+
+    #[test]
+    fn patch_immediate() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(assemble(&[MovI8 { rd: 1, n: 5 }, Halt]));
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 5);
+
+        assert_eq!(s.patch(&[(1, 7), (0x10000, 0)]), Err(EmuError::OutOfRange { addr: 0x10000 }));
+        assert_eq!(s.decode_at(0), MovI8 { rd: 1, n: 5 });
+        s.patch(&[(1, 42)]).unwrap();
+        s.set_instruction_pointer(0);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 42);
+    }
+
+    #[test]
+    fn last_was_branch() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(assemble(&[
+            Inc { rd: 1 },
+            JumpRelative { cond: 13, offset: 2 },
+            Halt,
+            JumpRelative { cond: 9, offset: -4 },
+            Halt,
+        ]));
+        s.step().unwrap();
+        assert_eq!(s.last_was_branch(), None);
+        s.step().unwrap();
+        assert_eq!(s.last_was_branch(), Some(BranchInfo { addr: 2, target: 6, taken: true }));
+        s.step().unwrap();
+        assert_eq!(s.last_was_branch(), Some(BranchInfo { addr: 6, target: 8, taken: false }));
+    }
+
+    #[test]
+    fn undefined_alu_op_faults() {
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.regfile[1] = 0x1234;
+        // op 0 with an immediate: "alu?0 r1, 5"
+        s.load_program(vec![0x00, 0x51, 0x20, 0x15]);
+        s.step().unwrap();
+        assert_eq!(s.step(), Err(EmuError::IllegalAluOp { addr: 2, op: 0 }));
+        assert_eq!(s.regfile[1], 0x1235);
+        assert!(!s.flags().contains(alu::EF));
+        assert_eq!(s.ip(), 2);
+    }
+
+    #[test]
+    fn read_write_16() {
+        let mut s = Simple::new();
+        s.raw_write_range(0x100, &[0x12, 0x34]).unwrap();
+        assert_eq!(s.read_16(0x100), Ok(0x1234));
+        assert_eq!(s.read_16(0x101), Ok(0x3400));
+
+        s.write_16(0xFFFF, 0xabcd).unwrap();
+        assert_eq!(s.memory()[0xFFFF], 0xab);
+        assert_eq!(s.memory()[0], 0xcd);
+        assert_eq!(s.read_16(0xFFFF), Ok(0xabcd));
+        s.raw_write_range(0xFFFF, &[0x56]).unwrap();
+        s.raw_write_range(0, &[0x78]).unwrap();
+        assert_eq!(s.peek_16(0xFFFF), 0x5678);
+        assert_eq!(s.raw_write_range(0xFFFF, &[0x9a, 0xbc]),
+            Err(EmuError::OutOfRange { addr: 0x10000 }));
+        assert_eq!(s.peek_16(0xFFFF), 0x5678);
+
+        s.write_16(0x200, 0xbeef).unwrap();
+        assert_eq!(&s.memory()[0x200..0x202], &[0xbe, 0xef]);
+
+        for addr in (0..0x10000).filter(|addr| !(0xFF00..0xFF20).contains(addr)) {
+            let value = addr as u16 ^ 0xa5c3;
+            s.write_16(addr, value).unwrap();
+            assert_eq!(s.read_16(addr), Ok(value), "at {:#06x}", addr);
+        }
+    }
+
+    #[test]
+    fn halt_callback() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(assemble(&[MovI8 { rd: 1, n: 9 }, Halt]));
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let record = seen.clone();
+        s.set_halt_callback(Box::new(move |s| record.borrow_mut().push(s.regfile[1])));
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.step(), Ok(false));
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(*seen.borrow(), vec![9]);
+    }
+
+    #[test]
+    fn compare_immediate() {
+        use Instruction::*;
+        let program = assemble(&[CompareImmediate { rd: 1, n: 0x1000 }, Halt]);
+        for &(value, greater, equal, less) in &[
+            (0x2000, true, false, false),
+            (0x1000, false, true, false),
+            (0x0fff, false, false, true),
+            (0xf000, false, false, true),
+        ] {
+            let mut s = Simple::new();
+            s.set_trace(false);
+            s.load_program(program.clone());
+            s.regfile[1] = value;
+            s.step().unwrap();
+            assert_eq!(s.ip(), 4);
+            assert_eq!(s.regfile[1], value);
+            assert_eq!(Condition::Greater.holds(s.flags()), greater, "{:#06x}", value);
+            assert_eq!(Condition::Zero.holds(s.flags()), equal, "{:#06x}", value);
+            assert_eq!(Condition::Less.holds(s.flags()), less, "{:#06x}", value);
+        }
+        assert_eq!(Instruction::decode(0xa910, 0x1000).to_string(), "cmpi r1, 4096");
+    }
+
+    #[test]
+    fn fetch_hook_cache_latency() {
+        use Instruction::*;
+        let program = assemble(&[
+            MovI8 { rd: 1, n: 10 },
+            Dec { rd: 1 },
+            JumpRegister { rd: 1, nonzero: true, offset: -4 },
+            Halt,
+        ]);
+        let run = |cache_lines: usize| {
+            let mut s = Simple::new();
+            s.set_trace(false);
+            s.load_program(program.clone());
+            // Direct mapped, 4-byte lines, 10 cycles per miss.
+            let mut tags = vec![None; cache_lines];
+            s.set_fetch_hook(Box::new(move |addr| {
+                if cache_lines == 0 {
+                    return 10;
+                }
+                let line = addr as usize / 4;
+                let slot = &mut tags[line % cache_lines];
+                if *slot == Some(line) {
+                    0
+                } else {
+                    *slot = Some(line);
+                    10
+                }
+            }));
+            assert_eq!(s.run(), StopReason::Halted);
+            s.cycles()
+        };
+        // 21 instructions run before the halt; 22 words are fetched.
+        assert_eq!(run(0), 21 + 22 * 10);
+        assert_eq!(run(4), 21 + 2 * 10);
+    }
+
+    #[test]
+    fn boot_from_reset_vector() {
+        use Instruction::*;
+        let mut image = vec![0u8; 65536].into_boxed_slice();
+        let code = assemble(&[Push { rd: 2 }, MovI8 { rd: 1, n: 7 }, Halt]);
+        image[0x100..0x100 + code.len()].copy_from_slice(&code);
+        image[Simple::RESET_VECTOR..].copy_from_slice(&[0x01, 0x00, 0x80, 0x00]);
+        let mut s = Simple::boot(image);
+        assert_eq!(s.ip(), 0x100);
+        assert_eq!(s.regfile[Simple::STACK_POINTER], 0x8000);
+        s.set_trace(false);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[Simple::STACK_POINTER], 0x7ffe);
+
+        s.reset();
+        assert_eq!(s.ip(), 0x100);
+        assert_eq!(s.regfile[1], 0);
+        assert!(!s.is_halted());
+    }
+
+    #[test]
+    fn bus_error_outside_mapped_ranges() {
+        use Instruction::*;
+        let mut s = Simple::with_memory(Box::new(SparseMemory(HashMap::new())));
+        s.set_trace(false);
+        s.add_mapped_range(0..0x1000);
+        s.add_mapped_range(0x8000..0x9000);
+        s.load_program(assemble(&[
+            MovI16 { rd: 1, n: 0x8ffe },
+            Load { rd: 2, rs: 1, ro: 0 },
+            MovI16 { rd: 1, n: 0x4000 },
+            Load { rd: 2, rs: 1, ro: 0 },
+        ]));
+        s.step().unwrap();
+        s.step().unwrap();
+        s.step().unwrap();
+        assert_eq!(s.step(), Err(EmuError::BusError { addr: 0x4000 }));
+        assert_eq!(s.read_16(0x8fff), Err(EmuError::BusError { addr: 0x9000 }));
+        s.set_instruction_pointer(0x2000);
+        assert_eq!(s.step(), Err(EmuError::BusError { addr: 0x2000 }));
+    }
+
+    #[test]
+    fn run_stats_fib() {
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(FIB_PROGRAM.to_vec());
+        s.regfile[1] = 12;
+        let stats = s.run_stats();
+        assert_eq!(s.regfile[1], 89);
+        // Five trips back round the loop, then the jz out of it; the three
+        // jzs fall through the other twelve times.
+        assert_eq!(stats, RunStats {
+            branches_taken: 5 + 1,
+            branches_not_taken: 12,
+            stop_reason: Some(StopReason::Halted),
+            ..RunStats::default()
+        });
+
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.regfile[Simple::STACK_POINTER] = 0x1000;
+        s.load_program(assemble(&[
+            Instruction::CallRelative { cond: 13, offset: 2 },
+            Instruction::Halt,
+            Instruction::Push { rd: 1 },
+            Instruction::Pop { rd: 1 },
+            Instruction::Ret,
+        ]));
+        let stats = s.run_stats();
+        assert_eq!((stats.calls, stats.returns), (1, 1));
+        assert_eq!((stats.memory_reads, stats.memory_writes), (2, 2));
+    }
+
+    #[test]
+    fn inc_program() {
+        let program = vec![0x00,0x51,0x00,0x51,0x00,0x51];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 3);
+    }
+
+    #[test]
+    fn stack_program() {
+        let program = vec![
+            0x81,0xff,0x00,0x31,0x00,0x31,0x00,0x31,
+            0x00,0x42,0x00,0x43,0x00,0x44,0x00,0x45,
+        ];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 255);
+        assert_eq!(s.regfile[2], 255);
+        assert_eq!(s.regfile[3], 255);
+        assert_eq!(s.regfile[4], 255);
+        assert_ne!(s.regfile[5], 255);
+        assert_eq!(s.regfile[15], 2);
+    }
+
+    #[test]
+    fn call_program() {
+        let program = vec![
+            0x88,0x0a,0x7d,0x24,0x7d,0x1a,0x88,0x64,
+            0x7d,0x1e,0x7d,0x14,0x81,0x00,0x92,0x00,
+            0x03,0xe8,0xb8,0x10,0x7d,0x16,0x7d,0x08,
+            0x00,0x51,0x0b,0x82,0x37,0xf4,0x00,0x00,
+            0x99,0x00,0xff,0x01,0x59,0x80,0xa0,0x01,
+            0x21,0x81,0xa0,0x01,0x2b,0x80,0x39,0x12,
+            0x89,0x00,0x8a,0x01,0x22,0x81,0x39,0x10,
+            0x01,0x9a,0x22,0x81,0x39,0x06,0x01,0xa9,
+            0x3d,0xf2,0xa0,0x01,0xb8,0x90,0xa0,0x01,
+            0xb8,0xa0,0xa0,0x01,
+        ];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[8], 1597);
+    }
+
+    #[test]
+    fn run_while_program() {
+        // loop:
+        // inc r1
+        // jmp loop
+        let program = vec![0x00,0x51,0x3d,0xfc];
+        let mut s = Simple::new();
+        s.load_program(program);
+        s.run_while(|s| s.regfile[1] < 10).unwrap();
+        assert_eq!(s.regfile[1], 10);
+    }
+
+    #[test]
+    fn relative_jumps_in_range() {
+        let mut s = Simple::new();
+        s.load_program(FIB_PROGRAM.to_vec());
+        assert_eq!(s.check_relative_jumps(0..32), vec![]);
+    }
+
+    #[test]
+    fn relative_jump_out_of_range() {
+        // mov r1, 1
+        // jmp [ip + 0x40]
+        // mov r2, 2
+        let program = vec![0x81,0x01,0x3d,0x40,0x82,0x02];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.check_relative_jumps(0..6),
+            vec![RangeError { addr: 2, target: 0x44 }]);
+    }
+
+    #[test]
+    fn decode_all_add_program() {
+        let program = vec![0x81,0x0a,0x82,0x0b,0x01,0x21];
+        assert_eq!(decode_all(&program), vec![
+            Ok(Instruction::MovI8 { rd: 1, n: 10 }),
+            Ok(Instruction::MovI8 { rd: 2, n: 11 }),
+            Ok(Instruction::Alu { op: 1, rd: 2, rs: 1 }),
+        ]);
+    }
+
+    #[test]
+    fn decode_all_reports_errors() {
+        let mut program = assemble(&[
+            Instruction::Illegal(0xffff),
+            Instruction::Inc { rd: 1 },
+            Instruction::MovI16 { rd: 2, n: 0x1234 },
+        ]);
+        program.truncate(7);
+        assert_eq!(decode_all(&program), vec![
+            Err(DecodeError::UndefinedOpcode { addr: 0, word: 0xffff }),
+            Ok(Instruction::Inc { rd: 1 }),
+            Err(DecodeError::Truncated { addr: 4 }),
+        ]);
+        assert_eq!(decode_all(&program[..5]).last(),
+            Some(&Err(DecodeError::Truncated { addr: 4 })));
+    }
+
+    #[test]
+    fn register_breakpoint() {
+        let mut s = Simple::new();
+        s.load_program(FIB_PROGRAM.to_vec());
+        s.regfile[1] = 11;
+        s.add_register_breakpoint(1, 55);
+        assert_eq!(s.run(), StopReason::RegisterBreak { reg: 1, value: 55 });
+        assert_eq!(s.regfile[1], 55);
+        assert!(!s.is_halted());
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 55);
+    }
+
+    #[test]
+    fn verify_program_clean() {
+        assert_eq!(Simple::verify_program(&FIB_PROGRAM), vec![]);
+    }
+
+    #[test]
+    fn verify_program_mid_instruction_jump() {
+        let program = assemble(&[
+            Instruction::MovI16 { rd: 1, n: 0x1234 },
+            Instruction::JumpRelative { cond: 0, offset: -4 },
+            Instruction::Illegal(0xffff),
+        ]);
+        assert_eq!(Simple::verify_program(&program), vec![
+            Diagnostic::MidInstruction { addr: 4, target: 2 },
+            Diagnostic::UndefinedOpcode { addr: 6, word: 0xffff },
+            Diagnostic::MissingHalt,
+        ]);
+
+        // mov r1, 1
+        // jmp [ip + 0x40]
+        // hlt
+        let program = vec![0x81,0x01,0x3d,0x40,0xa0,0x00];
+        assert_eq!(Simple::verify_program(&program),
+            vec![Diagnostic::OutOfRange(RangeError { addr: 2, target: 0x44 })]);
+    }
+
+    #[test]
+    fn memory_hook() {
+        let program = vec![
+            0x81,0xff,0x00,0x31,0x00,0x31,0x00,0x31,
+            0x00,0x42,0x00,0x43,0x00,0x44,0x00,0x45,
+        ];
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let captured = accesses.clone();
+        let mut s = Simple::new();
+        s.load_program(program);
+        s.set_memory_hook(Box::new(move |access| captured.borrow_mut().push(access)));
+        assert_eq!(s.run(), StopReason::Halted);
+        let accesses = accesses.borrow();
+        assert_eq!(accesses.iter().filter(|a| a.is_write).count(), 3);
+        assert_eq!(accesses.iter().filter(|a| !a.is_write).count(), 4);
+        assert_eq!(accesses[0], MemAccess { addr: 0xfffe, is_write: true, value: 255, width: 2 });
+        assert_eq!(accesses[3], MemAccess { addr: 0xfffa, is_write: false, value: 255, width: 2 });
+    }
+
+    #[test]
+    fn decode_fib_program() {
+        let mut s = Simple::new();
+        s.load_program(FIB_PROGRAM.to_vec());
+        let decoded: Vec<_> = s.instructions(0).take(5).collect();
+        assert_eq!(decoded, vec![
+            (0, Instruction::AluImmediate { op: 11, rd: 1, n: 0 }),
+            (2, Instruction::JumpRelative { cond: 9, offset: 0x12 }),
+            (4, Instruction::MovI8 { rd: 2, n: 0 }),
+            (6, Instruction::MovI8 { rd: 3, n: 1 }),
+            (8, Instruction::AluImmediate { op: 2, rd: 1, n: 1 }),
+        ]);
+        assert_eq!(s.instructions(0).count(), 11);
+    }
+
+    #[test]
+    fn fib_program_listing() {
+        let expected = [
+            "cmp r1, 0",     // 0x00
+            "jz [ip + 18]",  // 0x02: fib(0) = 0
+            "mov r2, 0",     // 0x04: a
+            "mov r3, 1",     // 0x06: b
+            "sub r1, 1",     // 0x08
+            "jz [ip + 12]",  // 0x0a: done, answer in r2
+            "add r2, r3",    // 0x0c: loop: a += b
+            "sub r1, 1",     // 0x0e
+            "jz [ip + 10]",  // 0x10: done, answer in r3
+            "add r3, r2",    // 0x12: b += a
+            "jmp [ip - 14]", // 0x14: to loop
+            "zero",          // 0x16
+            "mov r1, r2",    // 0x18
+            "zero",          // 0x1a
+            "mov r1, r3",    // 0x1c
+            "zero",          // 0x1e
+        ];
+        let mut s = Simple::new();
+        s.load_program(FIB_PROGRAM.to_vec());
+        let decoded: Vec<String> = (0..32).step_by(2)
+            .map(|addr| s.decode_at(addr).to_string())
+            .collect();
+        assert_eq!(decoded, expected, "fib program no longer decodes as documented");
+    }
+
+    #[test]
+    fn decode_immediate_length() {
+        // mov r2, 1000
+        // call [r0]
+        let program = vec![0x92,0x00,0x03,0xe8,0x6d,0x00];
+        let mut s = Simple::new();
+        s.load_program(program);
+        let decoded: Vec<_> = s.instructions(0).collect();
+        assert_eq!(decoded, vec![
+            (0, Instruction::MovI16 { rd: 2, n: 1000 }),
+            (4, Instruction::Call { cond: 13, target: JumpTarget::Register(0) }),
+        ]);
+    }
+
+    #[test]
+    fn no_execute_region() {
+        // jmp 0x100
+        let program = vec![0x1d,0x02,0x01,0x00];
+        let mut s = Simple::new();
+        s.load_program(program);
+        s.add_region(0x100..0x200, PERM_R | PERM_W);
+        assert_eq!(s.run(), StopReason::Fault(EmuError::ExecuteFault { addr: 0x100 }));
+    }
+
+    #[test]
+    fn read_only_region() {
+        // mov r1, 0x10
+        // mov r2, 5
+        // mov [r1 + r0], r2
+        let program = vec![0x81,0x10,0x82,0x05,0x51,0x20];
+        let mut s = Simple::new();
+        s.load_program(program);
+        s.add_region(0..0x100, PERM_R | PERM_X);
+        assert_eq!(s.run(), StopReason::Fault(EmuError::WriteFault { addr: 0x10 }));
+        assert_eq!(s.read_16(0x10), Ok(0));
+    }
+
+    #[test]
+    fn execute_lone_word() {
+        let mut s = Simple::new();
+        s.regfile[1] = 10;
+        s.regfile[2] = 11;
+        // add r2, r1
+        s.execute_word(0x0121, 0).unwrap();
+        assert_eq!(s.regfile[2], 21);
+        assert_eq!(s.ip(), 2);
+        // mov r3, 1000
+        s.execute_word(0x9300, 1000).unwrap();
+        assert_eq!(s.regfile[3], 1000);
+        assert_eq!(s.ip(), 6);
+    }
+
+    #[test]
+    fn last_instruction() {
+        let program = vec![0x81,0x0a,0x82,0x0b,0x01,0x21];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.last_instruction(), None);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.last_instruction(), Some((4, 0x0121)));
+    }
+
+    #[test]
+    fn accumulator_pair() {
+        let mut s = Simple::new();
+        s.set_acc32(0x1234_5678);
+        assert_eq!(s.regfile[18], 0x1234);
+        assert_eq!(s.regfile[19], 0x5678);
+        assert_eq!(s.acc32(), 0x1234_5678);
+    }
+
+    // mov r1, 1
+    // (zero padding)
+    // mov r2, 2
+    // hlt
+    const ZERO_PADDED: [u8; 10] = [0x81,0x01,0x00,0x00,0x00,0x00,0x82,0x02,0xa0,0x00];
+
+    #[test]
+    fn halt_on_zero() {
+        let mut s = Simple::with_halt_policy(HaltPolicy::HaltOnZero);
+        s.load_program(ZERO_PADDED.to_vec());
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 1);
+        assert_eq!(s.regfile[2], 0);
+        assert_eq!(s.ip(), 2);
+    }
+
+    #[test]
+    fn treat_zero_as_nop() {
+        let mut s = Simple::with_halt_policy(HaltPolicy::TreatZeroAsNop);
+        s.load_program(ZERO_PADDED.to_vec());
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 1);
+        assert_eq!(s.regfile[2], 2);
+        assert_eq!(s.ip(), 8);
+    }
+
+    #[test]
+    fn require_explicit_halt() {
+        let mut s = Simple::with_halt_policy(HaltPolicy::RequireExplicitHalt);
+        s.load_program(ZERO_PADDED.to_vec());
+        assert_eq!(s.run(), StopReason::Fault(EmuError::IllegalInstruction { addr: 2, word: 0 }));
+        assert_eq!(s.regfile[2], 0);
+    }
+
+    #[test]
+    fn fetch_decode_execute() {
+        // mov r1, 10; mov r2, 11; add r2, r1; mov acc, 0x12345678
+        let program = vec![0x81,0x0a,0x82,0x0b,0x01,0x21,0xa7,0x00,0x12,0x34,0x56,0x78];
+        let mut stepped = Simple::new();
+        stepped.load_program(program.clone());
+        let mut phased = Simple::new();
+        phased.load_program(program);
+        for _ in 0..4 {
+            stepped.step().unwrap();
+            let (word, next, next2) = phased.fetch().unwrap();
+            let instruction = phased.decode(word, next, next2);
+            phased.execute(instruction).unwrap();
+            assert_eq!(phased.regfile, stepped.regfile);
+        }
+        assert_eq!(phased.regfile[2], 21);
+        assert_eq!(phased.acc32(), 0x12345678);
+    }
+
+    #[test]
+    fn illegal_instruction() {
+        let program = vec![0x81,0x0a,0xff,0xff];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Fault(EmuError::IllegalInstruction { addr: 2, word: 0xffff }));
+    }
+
+    #[test]
+    fn alu_immediate_extension() {
+        let mut s = Simple::new();
+        s.regfile[1] = 5;
+        // add r1, 0xF
+        s.execute_word(0x211f, 0).unwrap();
+        assert_eq!(s.regfile[1], 20);
+
+        let mut s = Simple::new();
+        s.set_alu_immediate_mode(ImmediateMode::SignExtend);
+        s.regfile[1] = 5;
+        s.execute_word(0x211f, 0).unwrap();
+        assert_eq!(s.regfile[1], 4);
+    }
+
+    #[test]
+    fn mov_immediate_extension() {
+        let mut s = Simple::new();
+        // mov r1, 0xFF
+        s.execute_word(0x81ff, 0).unwrap();
+        assert_eq!(s.regfile[1], 255);
+
+        s.set_mov_immediate_mode(ImmediateMode::SignExtend);
+        s.execute_word(0x81ff, 0).unwrap();
+        assert_eq!(s.regfile[1], 0xFFFF);
+        s.execute_word(0x817f, 0).unwrap();
+        assert_eq!(s.regfile[1], 0x7F);
+    }
+
+    // fib(r1), result in r1; see fib_program_listing for the disassembly.
+    const FIB_PROGRAM: [u8; 32] = [
+        0x2b,0x10,0x39,0x12,0x82,0x00,0x83,0x01,
+        0x22,0x11,0x39,0x0c,0x01,0x23,0x22,0x11,
+        0x39,0x0a,0x01,0x32,0x3d,0xf2,0x00,0x00,
+        0xb1,0x20,0x00,0x00,0xb1,0x30,0x00,0x00,
+    ];
+
+    // mov r2, 5
+    // loop:
+    // inc r1
+    // dec r2
+    // cmp r2, 0
+    // jnz loop
+    const COUNT_LOOP: [u8; 10] = [0x82,0x05,0x00,0x51,0x00,0x62,0x2b,0x20,0x3a,0xf8];
+
+    #[test]
+    fn emulate_round_robin() {
+        let mut machines = [Simple::new(), Simple::new()];
+        let budgets = [4, 7];
+        for s in machines.iter_mut() {
+            s.set_trace(false);
+            s.load_program(COUNT_LOOP.to_vec());
+        }
+        let mut rounds = 0;
+        while machines.iter().any(|s| !s.is_halted()) {
+            rounds += 1;
+            for (s, &budget) in machines.iter_mut().zip(budgets.iter()) {
+                let before = s.cycles();
+                let emulated = s.emulate(budget);
+                assert!(emulated.cycles_used <= budget);
+                assert_eq!(s.cycles() - before, emulated.cycles_used);
+                match emulated.stop_reason {
+                    StopReason::StepLimit => assert_eq!(emulated.cycles_used, budget),
+                    StopReason::Halted => assert!(s.is_halted()),
+                    other => panic!("unexpected stop {:?}", other),
+                }
+            }
+        }
+        // 21 cycles at 4 per round
+        assert_eq!(rounds, 6);
+        for s in machines.iter() {
+            assert_eq!(s.cycles(), 21);
+            assert_eq!(s.regfile[1], 5);
+        }
+    }
+
+    #[test]
+    fn emulate_counts_cycles() {
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(COUNT_LOOP.to_vec());
+        // Every instruction costs 3 cycles.
+        s.set_fetch_hook(Box::new(|_| 2));
+        let mut total = 0;
+        loop {
+            let emulated = s.emulate(4);
+            assert!(emulated.cycles_used <= 4);
+            total += emulated.cycles_used;
+            if emulated.stop_reason == StopReason::Halted {
+                break;
+            }
+            assert_eq!(emulated.cycles_used, 4);
+        }
+        // Plus the fetch of the zero word it halts on.
+        assert_eq!(s.cycles(), 21 * 3 + 2);
+        assert_eq!(total + s.overrun, s.cycles());
+    }
+
+    #[test]
+    fn symbol_breakpoint() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("start", 0);
+        symbols.insert("loop", 2);
+        let mut s = Simple::new();
+        s.load_program(COUNT_LOOP.to_vec());
+        s.set_symbols(symbols);
+        assert_eq!(s.resolve_symbol("loop"), Some(2));
+        assert_eq!(s.resolve_symbol("missing"), None);
+        assert!(!s.add_symbol_breakpoint("missing"));
+        assert!(s.add_symbol_breakpoint("loop"));
+        assert_eq!(s.run(), StopReason::Breakpoint);
+        assert_eq!(s.ip(), 2);
+        assert_eq!(s.regfile[1], 0);
+        assert_eq!(s.run(), StopReason::Breakpoint);
+        assert_eq!(s.regfile[1], 1);
+
+        let path = std::env::temp_dir()
+            .join(format!("simple_emu_symbols_{}.lst", std::process::id()));
+        s.write_listing(&path, 0, 4).unwrap();
+        let listing = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "start:");
+        assert_eq!(lines[2], "loop:");
+    }
+
+    #[test]
+    fn breakpoint_ignore_count() {
+        let mut s = Simple::new();
+        s.load_program(COUNT_LOOP.to_vec());
+        let mut breakpoint = Breakpoint::new(2);
+        breakpoint.ignore_count = 2;
+        s.add_breakpoint(breakpoint);
+        assert_eq!(s.run(), StopReason::Breakpoint);
+        assert_eq!(s.ip(), 2);
+        assert_eq!(s.regfile[1], 2);
+        assert_eq!(s.run(), StopReason::Breakpoint);
+        assert_eq!(s.ip(), 2);
+        assert_eq!(s.regfile[1], 3);
+    }
+
+    #[test]
+    fn breakpoint_condition() {
+        let mut s = Simple::new();
+        s.load_program(COUNT_LOOP.to_vec());
+        let mut breakpoint = Breakpoint::new(2);
+        breakpoint.condition = Some(Box::new(|s| s.regfile[1] == 4));
+        s.add_breakpoint(breakpoint);
+        assert_eq!(s.run(), StopReason::Breakpoint);
+        assert_eq!(s.ip(), 2);
+        assert_eq!(s.regfile[1], 4);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.ip(), 10);
+        assert_eq!(s.regfile[1], 5);
+    }
+
+    #[test]
+    fn crash_report() {
+        let program = vec![0x81,0x0a,0xff,0xff];
+        let mut s = Simple::new();
+        s.load_program(program);
+        let err = match s.run() {
+            StopReason::Fault(err) => err,
+            reason => panic!("unexpected {:?}", reason),
+        };
+        let report = s.crash_report(&err);
+        assert!(report.contains("illegal instruction 0xffff at 0x0002"));
+        assert!(report.contains("at 0x0002: ffff  .word 0xffff"));
+        assert!(report.contains("r1  000a"));
+        assert!(report.contains("ip  0002"));
+    }
+
+    #[test]
+    fn disassemble() {
+        let program = vec![0x81,0x0a,0x82,0x0b,0x01,0x21,0x3d,0xf2,0x6d,0x02,0x01,0x00];
+        let mut s = Simple::new();
+        s.load_program(program);
+        let listing: Vec<_> = s.instructions(0)
+            .map(|(_, instruction)| instruction.to_string())
+            .collect();
+        assert_eq!(listing, vec![
+            "mov r1, 10",
+            "mov r2, 11",
+            "add r2, r1",
+            "jmp [ip - 14]",
+            "call 0x0100",
+        ]);
+    }
+
+    #[test]
+    fn disassemble_range_resolves_targets() {
+        let mut s = Simple::new();
+        s.load_program(FIB_PROGRAM.to_vec());
+        let listing = s.disassemble_range(0..32);
+        assert_eq!(listing.len(), 16);
+        assert_eq!(listing[1], (0x02, "jz 0x0016".to_string()));
+        assert_eq!(listing[10], (0x14, "jmp 0x0008".to_string()));
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("loop", 0x08);
+        s.set_symbols(symbols);
+        assert_eq!(s.disassemble_range(0x14..0x16), vec![(0x14, "jmp 0x0008 <loop>".to_string())]);
+        let call = Instruction::Call { cond: 13, target: JumpTarget::Immediate(0x08) };
+        assert_eq!(call.display_at(0, &s.symbols), "call 0x0008 <loop>");
+    }
+
+    #[test]
+    fn exit_code() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(assemble(&[MovI8 { rd: 1, n: 42 }, HaltWithCode { rd: 1 }, Halt]));
+        assert_eq!(s.exit_code(), None);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.exit_code(), Some(42));
+        assert_eq!(s.ip(), 2);
+
+        s.set_instruction_pointer(4);
+        assert_eq!(s.exit_code(), None);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.exit_code(), None);
+    }
+
+    #[test]
+    fn execute_from_code_slice() {
+        let code = assemble(&[
+            Instruction::MovI16 { rd: 1, n: 0x100 },
+            Instruction::Load { rd: 2, rs: 1, ro: 0 },
+            Instruction::Inc { rd: 2 },
+            Instruction::Halt,
+        ]);
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.raw_write_range(0x100, &[0x12, 0x34]).unwrap();
+        s.set_code_slice(0, code);
+        assert_eq!(s.decode_at(8), Instruction::Halt);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[2], 0x1235);
+        assert_eq!(s.read_16(0), Ok(0));
+        assert!(s.crash_report(&EmuError::QuotaExceeded).contains("at 0x0008: a000  hlt"));
+
+        let path = std::env::temp_dir()
+            .join(format!("simple_emu_code_slice_{}.lst", std::process::id()));
+        s.write_listing(&path, 0, 10).unwrap();
+        let listing = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(listing.lines().any(|line| line == "0000  91 00 01 00  mov r1, 256"));
+    }
+
+    #[test]
+    fn step_detailed_interrupt() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(assemble(&[MovI8 { rd: 1, n: 1 }, Halt]));
+        s.raw_write_range(0x100, &assemble(&[MovI8 { rd: 2, n: 9 }])).unwrap();
+        s.raw_write_range(Simple::IRQ_VECTORS + 2 * 3, &[0x01, 0x00]).unwrap();
+        s.regfile[Simple::STACK_POINTER] = 0x1000;
+        s.interrupts.mask = 0;
+        s.raise_irq(3);
+        let info = s.step_detailed().unwrap();
+        assert_eq!((info.addr, info.instruction), (0x100, MovI8 { rd: 2, n: 9 }));
+        assert_eq!(s.regfile[2], 9);
+        assert_eq!(s.regfile[1], 0);
+    }
+
+    #[test]
+    fn step_n() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(assemble(&[
+            MovI8 { rd: 1, n: 2 },
+            MovI8 { rd: 2, n: 3 },
+            Alu { op: 1, rd: 1, rs: 2 },
+            Halt,
+        ]));
+        let steps: Vec<StepInfo> = s.step_n(3).into_iter().map(Result::unwrap).collect();
+        let mnemonics: Vec<String> = steps.iter()
+            .map(|step| step.instruction.to_string().split(' ').next().unwrap().to_string())
+            .collect();
+        assert_eq!(mnemonics, ["mov", "mov", "add"]);
+        assert_eq!(steps[2].addr, 4);
+        assert_eq!(steps[2].changed, vec![(1, 5), (Simple::INSTRUCTION_POINTER, 6)]);
+
+        let rest = s.step_n(10);
+        assert_eq!(rest.len(), 1);
+        assert!(rest[0].as_ref().unwrap().halted);
+        assert!(s.step_n(10).is_empty());
+    }
+
+    #[test]
+    fn watchdog() {
+        use Instruction::*;
+        let program = |kick: bool| assemble(&[
+            MovI16 { rd: 1, n: 0x200 },
+            MovI8 { rd: 2, n: 20 },
+            Store { rd: 1, rs: 0, ro: if kick { 0 } else { 3 } },
+            Dec { rd: 2 },
+            JumpRegister { rd: 2, nonzero: true, offset: -6 },
+            HaltWithCode { rd: 2 },
+        ]);
+
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(program(true));
+        s.regfile[3] = 2;
+        s.set_watchdog(0x200, 5, WatchdogAction::Fault);
+        assert_eq!(s.run(), StopReason::Halted);
+
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(program(false));
+        s.regfile[3] = 2;
+        s.set_watchdog(0x200, 5, WatchdogAction::Fault);
+        assert_eq!(s.estimate_until_halt(100), None);
+        assert_eq!(s.run(), StopReason::Fault(EmuError::WatchdogExpired));
+        assert_eq!(s.cycles(), 6);
+
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(program(false));
+        s.load_program_at(0x100, assemble(&[MovI8 { rd: 2, n: 7 }, HaltWithCode { rd: 2 }]), false);
+        s.raw_write_range(Simple::RESET_VECTOR, &[0x01, 0x00]).unwrap();
+        s.regfile[3] = 2;
+        s.set_watchdog(0x200, 5, WatchdogAction::Reset);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.exit_code(), Some(7));
+
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(program(true));
+        s.set_watchdog(0x200, 5, WatchdogAction::Fault);
+        assert_eq!(s.run(), StopReason::Halted);
+        s.reset();
+        assert_eq!(s.run(), StopReason::Halted);
+
+        // Never kicks, so it's reset every few cycles without end.
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(assemble(&[
+            MovI8 { rd: 1, n: 1 },
+            JumpRegister { rd: 1, nonzero: true, offset: -2 },
+        ]));
+        s.set_watchdog(0x200, 5, WatchdogAction::Reset);
+        assert_eq!(s.emulate(3).cycles_used, 3);
+        let emulated = s.emulate(1000);
+        assert_eq!(emulated, Emulated { cycles_used: 1000, stop_reason: StopReason::StepLimit });
+        assert_eq!(s.cycles(), 1003);
+    }
+
+    #[test]
+    fn register_write_hook() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(assemble(&[
+            MovI8 { rd: 1, n: 2 },
+            MovI8 { rd: 2, n: 3 },
+            Alu { op: 1, rd: 1, rs: 2 },
+            Halt,
+        ]));
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let record = writes.clone();
+        s.set_register_write_hook(Box::new(move |reg, old, new| {
+            if reg == 2 {
+                record.borrow_mut().push((old, new));
+            }
+        }));
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(*writes.borrow(), vec![(0, 3)]);
+    }
+
+    #[test]
+    fn lahf_sahf() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.regfile[3] = 1;
+        s.load_program(assemble(&[
+            Alu { op: 11, rd: 2, rs: 3 },
+            LoadFlags { rd: 1 },
+            Alu { op: 1, rd: 3, rs: 3 },
+            StoreFlags { rd: 1 },
+            Halt,
+        ]));
+        s.step().unwrap();
+        let flags = s.flags();
+        assert_eq!(flags, alu::CF | alu::SF);
+        s.step().unwrap();
+        assert_eq!(s.regfile[1], flags.bits());
+        s.step().unwrap();
+        assert_eq!(s.flags(), alu::Flags::empty());
+        s.step().unwrap();
+        assert_eq!(s.flags(), flags);
+        assert_eq!(s.decode_at(2).to_string(), "lahf r1");
+
+        s.regfile[4] = 0xffff;
+        s.execute(StoreFlags { rd: 4 }).unwrap();
+        assert_eq!(s.flags(), alu::Flags::all());
+    }
+
+    #[test]
+    fn compressed_mode() {
+        use Instruction::*;
+        let program = [
+            MovI8 { rd: 1, n: 5 },
+            MovI8 { rd: 2, n: 3 },
+            Inc { rd: 1 },
+            Inc { rd: 1 },
+            Push { rd: 1 },
+            Pop { rd: 3 },
+            Dec { rd: 2 },
+            Not { rd: 4 },
+            MovI16 { rd: 5, n: 1000 },
+            Inc { rd: 5 },
+            Alu { op: 1, rd: 5, rs: 1 },
+            Neg { rd: 6 },
+            Dec { rd: 6 },
+            MovI8 { rd: 7, n: 7 },
+            Inc { rd: 7 },
+            Halt,
+        ];
+        let run = |image: Vec<u8>| {
+            let mut s = Simple::new();
+            s.set_trace(false);
+            s.regfile[Simple::STACK_POINTER] = 0x1000;
+            s.load_program(image);
+            let writes = Rc::new(RefCell::new(Vec::new()));
+            let log = writes.clone();
+            s.set_register_write_hook(Box::new(move |reg, old, new| {
+                if reg != Simple::INSTRUCTION_POINTER {
+                    log.borrow_mut().push((reg, old, new));
+                }
+            }));
+            assert_eq!(s.run(), StopReason::Halted);
+            let writes = writes.borrow().clone();
+            (s.regfile[..16].to_vec(), writes)
+        };
+        let full = assemble(&program);
+        let compressed = assemble_compressed(&program);
+        // cmode on, eight bytes, cmode off; mov r5, 1000; inc r5; add r5, r1;
+        // cmode on, five bytes.
+        assert_eq!(compressed.len(), (2 + 8 + 1) + 4 + 2 + 2 + (2 + 5));
+        assert!(compressed.len() < full.len());
+        assert_eq!(run(compressed), run(full));
+
+        assert_eq!(Instruction::decode_compressed(0x9a), MovI8 { rd: 10, n: 1 });
+        assert_eq!(Instruction::decode_compressed(0x7f), Illegal(0x7f));
+        for byte in 0..=255u8 {
+            if let Some(encoded) = Instruction::decode_compressed(byte).encode_compressed() {
+                assert_eq!(encoded, byte);
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_stepping() {
+        use Instruction::*;
+        let program = assemble_compressed(&[
+            MovI8 { rd: 0, n: 7 },
+            Inc { rd: 1 },
+            Inc { rd: 2 },
+            Halt,
+        ]);
+        let machine = || {
+            let mut s = Simple::new();
+            s.set_trace(false);
+            s.load_program(program.clone());
+            s
+        };
+
+        let mut s = machine();
+        s.set_sanity_checks(true);
+        assert_eq!(s.run(), StopReason::Halted);
+
+        let mut s = machine();
+        s.step().unwrap();
+        let info = s.step_detailed().unwrap();
+        assert_eq!((info.addr, info.instruction), (2, MovI8 { rd: 0, n: 7 }));
+        assert_eq!(s.ip(), 3);
+
+        let mut s = machine();
+        let block = s.step_block().unwrap();
+        assert_eq!(block.instructions.iter().map(|&(addr, _)| addr).collect::<Vec<_>>(),
+            vec![0, 2, 3, 4, 5]);
+        assert!(s.is_halted());
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let mut s = Simple::new();
+        s.raw_write_range(0x100, &[0x00, 0x05]).unwrap();
+        s.regfile[1] = 0x100;
+        s.regfile[2] = 5;
+        s.regfile[3] = 9;
+        // cas [r1], r2, r3
+        s.execute_word(0xc123, 0).unwrap();
+        assert!(s.zf());
+        assert_eq!(s.read_16(0x100), Ok(9));
+
+        s.regfile[3] = 12;
+        s.execute_word(0xc123, 0).unwrap();
+        assert!(!s.zf());
+        assert_eq!(s.read_16(0x100), Ok(9));
+    }
+
+    #[test]
+    fn opcode_histogram() {
+        let mut s = Simple::new();
+        s.load_program(FIB_PROGRAM.to_vec());
+        s.regfile[1] = 11;
+        s.set_profiling(true);
+        assert_eq!(s.run(), StopReason::Halted);
+
+        let opcodes = s.opcode_histogram();
+        assert_eq!(opcodes[&0x2b10], 1);
+        assert_eq!(opcodes[&0x8200], 1);
+        assert_eq!(opcodes[&0x3df2], 5);
+
+        let classes = s.opcode_class_histogram();
+        assert_eq!(classes[&0b0000], 10);
+        assert_eq!(classes[&0b0010], 12);
+        assert_eq!(classes[&0b0011], 17);
+        assert_eq!(classes[&0b1000], 2);
+        assert_eq!(classes[&0b1011], 1);
+    }
+
+    #[test]
+    fn segmented_load() {
+        // mov r1, 0x1234
+        // mov r21, r1
+        // mov r2, [r0 + r0]
+        let program = vec![0x91,0x00,0x12,0x34,0xb5,0x14,0x42,0x00];
+        let mut s = Simple::new();
+        s.enable_segmentation();
+        s.load_program(program);
+        s.raw_write_range(0x12340, &[0xbe, 0xef]).unwrap();
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[21], 0x1234);
+        assert_eq!(s.regfile[2], 0xbeef);
+    }
+
+    #[test]
+    fn segmented_decode() {
+        let mut s = Simple::new();
+        s.enable_segmentation();
+        s.regfile[Simple::CODE_SEGMENT] = 0x1000;
+        s.raw_write_range(0x10000, &assemble(&[
+            Instruction::MovI8 { rd: 1, n: 5 },
+            Instruction::Halt,
+        ])).unwrap();
+        assert_eq!(s.decode_at(0), Instruction::MovI8 { rd: 1, n: 5 });
+        assert_eq!(s.disassemble_range(0..4)[1], (2, "hlt".to_string()));
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 5);
+    }
+
+    #[test]
+    fn segmented_stack() {
+        let mut s = Simple::new();
+        s.enable_segmentation();
+        s.regfile[22] = 0x8000;
+        s.regfile[1] = 0x55aa;
+        // push r1
+        s.execute_word(0x0031, 0).unwrap();
+        assert_eq!(s.regfile[15], 0xfffe);
+        assert_eq!(s.memory()[0x8fffe..0x90000], [0x55, 0xaa]);
+        assert_eq!(s.memory()[0xfffe..0x10000], [0, 0]);
+    }
+
+    #[test]
+    fn diff_state() {
+        // mov r1, 10
+        // push r1
+        let program = vec![0x81,0x0a,0x00,0x31];
+        let mut before = Simple::new();
+        before.load_program(program.clone());
+        let mut after = Simple::new();
+        after.load_program(program);
+        assert!(before.diff_state(&after).is_empty());
+
+        after.step().unwrap();
+        let diff = before.diff_state(&after);
+        assert_eq!(diff.registers, vec![(1, 0, 10), (16, 0, 2)]);
+        assert_eq!(diff.memory, vec![]);
+
+        after.step().unwrap();
+        let diff = before.diff_state(&after);
+        assert_eq!(diff.registers, vec![(1, 0, 10), (15, 0, 0xfffe), (16, 0, 4)]);
+        assert_eq!(diff.memory, vec![0xffff..0x10000]);
+    }
+
+    #[test]
+    fn numeric_console() {
+        // mov r1, 0xFF01
+        // mov r2, 42
+        // mov [r1 + r0], r2
+        let program = vec![0x91,0x00,0xff,0x01,0x82,0x2a,0x51,0x20];
+        let output = SharedBuffer::default();
+        let mut s = Simple::new();
+        s.set_output(Box::new(output.clone()));
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(output.contents(), "OUT: 42\n");
+    }
+
+    #[test]
+    fn ascii_console() {
+        // mov r1, 0xFF01
+        // mov r2, 'H'
+        // mov [r1 + r0], r2
+        // mov r2, 'i'
+        // mov [r1 + r0], r2
+        // mov r2, '\n'
+        // mov [r1 + r0], r2
+        let program = vec![
+            0x91,0x00,0xff,0x01,0x82,0x48,0x51,0x20,
+            0x82,0x69,0x51,0x20,0x82,0x0a,0x51,0x20,
+        ];
+        let output = SharedBuffer::default();
+        let mut s = Simple::new();
+        s.set_output(Box::new(output.clone()));
+        s.set_console_mode(ConsoleMode::Ascii);
+        s.load_program(program);
+        s.run_while(|s| s.ip() < 12).unwrap();
+        assert_eq!(output.contents(), "");
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(output.contents(), "Hi\n");
+    }
+
+    #[test]
+    fn console_format() {
+        let output = SharedBuffer::default();
+        let mut s = Simple::new();
+        s.set_output(Box::new(output.clone()));
+        s.regfile[1] = Simple::CONSOLE_PORT as u16;
+        s.regfile[2] = 42;
+        // mov [r1 + r0], r2
+        s.execute_word(0x5120, 0).unwrap();
+        s.set_console_format(10, 5);
+        s.execute_word(0x5120, 0).unwrap();
+        s.set_console_format(16, 4);
+        s.execute_word(0x5120, 0).unwrap();
+        s.set_console_format(2, 0);
+        s.execute_word(0x5120, 0).unwrap();
+        assert_eq!(output.contents(),
+            "OUT: 42\nOUT: 00042\nOUT: 0x002a\nOUT: 0b101010\n");
+    }
+
+    #[test]
+    fn memory_quota() {
+        // mov r1, 0x100
+        // loop:
+        // mov r2, [r1 + r0]
+        // inc r2
+        // mov [r1 + r0], r2
+        // jmp loop
+        let program = vec![0x91,0x00,0x01,0x00,0x42,0x10,0x00,0x52,0x51,0x20,0x3d,0xf8];
+        let mut s = Simple::new();
+        s.load_program(program);
+        s.set_memory_quota(Some(100));
+        assert_eq!(s.run(), StopReason::QuotaExceeded);
+        assert_eq!(s.memory_accesses(), 100);
+        assert_eq!(s.peek_16(0x100), 50);
+    }
+
+    #[test]
+    fn jump_register_countdown() {
+        // mov r1, 5
+        // loop:
+        // inc r2
+        // dec r1
+        // jrnz r1, loop
+        let program = vec![0x81,0x05,0x00,0x52,0x00,0x61,0xd1,0xfa];
+        let mut s = Simple::new();
+        s.load_program(program);
+        s.regfile[17] = alu::CF.bits();
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 0);
+        assert_eq!(s.regfile[2], 5);
+        assert_eq!(s.flags(), alu::CF);
+    }
+
+    #[test]
+    fn jump_register_zero() {
+        let mut s = Simple::new();
+        // jrz r3, [ip + 6]
+        s.execute_word(0xd306, 0).unwrap();
+        assert_eq!(s.ip(), 8);
+        s.regfile[3] = 1;
+        s.execute_word(0xd306, 0).unwrap();
+        assert_eq!(s.ip(), 10);
+        assert_eq!(Instruction::decode(0xd306, 0).to_string(), "jrz r3, [ip + 6]");
+        assert_eq!(Instruction::decode(0xd1fa, 0).to_string(), "jrnz r1, [ip - 6]");
+    }
+
+    #[test]
+    fn set_instruction_pointer() {
+        let program = vec![0x81,0x0a,0x82,0x0b,0x01,0x21];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.instruction_pointer(), 6);
+        s.set_instruction_pointer(4);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[2], 31);
+    }
+
+    #[test]
+    fn mov_to_instruction_pointer() {
+        // mov r1, 8
+        // mov r16, r1
+        // mov r2, 2
+        // (halt)
+        // mov r3, 3
+        let program = vec![0x81,0x08,0xb0,0x14,0x82,0x02,0x00,0x00,0x83,0x03];
+        let mut s = Simple::new();
+        s.load_program(program);
+        s.step().unwrap();
+        s.step().unwrap();
+        assert_eq!(s.instruction_pointer(), 8);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[2], 0);
+        assert_eq!(s.regfile[3], 3);
+    }
+
+    #[test]
+    fn default_step_limit() {
+        // loop:
+        // jmp loop
+        let program = vec![0x3d,0xfe];
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::StepLimit);
+    }
+
+    #[test]
+    fn step_limit() {
+        // loop:
+        // inc r1
+        // jmp loop
+        let program = vec![0x00,0x51,0x3d,0xfc];
+        let mut s = Simple::new();
+        s.load_program(program);
+        s.set_step_limit(10);
+        assert_eq!(s.run(), StopReason::StepLimit);
+        assert_eq!(s.regfile[1], 5);
+        assert_eq!(s.run(), StopReason::StepLimit);
+        assert_eq!(s.regfile[1], 10);
+    }
+
+    #[test]
+    fn output_ports() {
+        // mov r1, 0xFF02
+        // mov r2, 'A'
+        // mov [r1 + r0], r2
+        // inc r1
+        // mov [r1 + r0], r2
+        // inc r1
+        // mov [r1 + r0], r2
+        let program = vec![
+            0x91,0x00,0xff,0x02,0x82,0x41,0x51,0x20,
+            0x00,0x51,0x51,0x20,0x00,0x51,0x51,0x20,
+        ];
+        let output = SharedBuffer::default();
+        let mut s = Simple::new();
+        s.set_output(Box::new(output.clone()));
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(output.contents(), "0x0041\n65\nA");
+    }
+
+    #[test]
+    fn memory_slices() {
+        let mut s = Simple::new();
+        for (i, byte) in s.memory_mut()[0x200..0x210].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        assert_eq!(s.read_16(0x200), Ok(0x0001));
+        assert_eq!(s.read_16(0x20e), Ok(0x0e0f));
+        assert_eq!(s.memory()[0x205], 5);
+        assert_eq!(s.memory().len(), 65536);
+    }
+
+    #[test]
+    fn abs_and_sneg() {
+        let mut s = Simple::new();
+        s.regfile[1] = 0xfffe;
+        s.regfile[2] = 0x8000;
+        s.regfile[3] = 7;
+        // abs r1
+        s.execute_word(0x0071, 0).unwrap();
+        assert_eq!(s.regfile[1], 2);
+        assert_eq!(s.flags(), alu::Flags::empty());
+        // abs r2
+        s.execute_word(0x0072, 0).unwrap();
+        assert_eq!(s.regfile[2], 0x8000);
+        assert!(s.of());
+        // sneg r3
+        s.execute_word(0x0083, 0).unwrap();
+        assert_eq!(s.regfile[3], -7i16 as u16);
+        assert!(s.sf() && !s.of());
+    }
+
+    fn assemble(instructions: &[Instruction]) -> Vec<u8> {
+        let lines: Vec<Line> = instructions.iter().map(|&instruction| instruction.into()).collect();
+        super::assemble(&lines)
+    }
+
+    #[test]
+    fn assemble_data_directives() {
+        use Instruction::*;
+        let program = super::assemble(&[
+            MovI16 { rd: 1, n: 8 }.into(),
+            LoadDisplaced { rd: 2, rs: 1, disp: 2 }.into(),
+            Halt.into(),
+            Line::Word(0x1234),
+            Line::Word(0x5678),
+            Line::Byte(0x9a),
+            Line::Byte(0xbc),
+        ]);
+        assert_eq!(&program[8..], &[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        assert_eq!(Line::Word(0x1234).to_string(), ".word 0x1234");
+        assert_eq!(Line::Byte(0x56).to_string(), ".byte 0x56");
+
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[2], 0x5678);
+    }
+
+    #[test]
+    fn interrupt_priority() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(assemble(&[
+            MovI16 { rd: 1, n: Simple::IRQ_MASK_PORT as u16 },
+            Store { rd: 1, rs: 0, ro: 0 },
+            JumpRelative { cond: 13, offset: -2 },
+        ]));
+        // Each handler prints its line number and acknowledges it.
+        for line in 0..InterruptController::LINES {
+            let handler = 0x100 + 0x40 * line as usize;
+            s.raw_write_range(handler, &assemble(&[
+                MovI8 { rd: 2, n: line },
+                MovI16 { rd: 3, n: Simple::CONSOLE_PORT as u16 },
+                Store { rd: 3, rs: 2, ro: 0 },
+                MovI16 { rd: 3, n: Simple::IRQ_ACK_PORT as u16 },
+                Store { rd: 3, rs: 2, ro: 0 },
+                Ret,
+            ])).unwrap();
+            s.raw_write_range(Simple::IRQ_VECTORS + 2 * line as usize,
+                &(handler as u16).to_be_bytes()).unwrap();
+        }
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let captured = lines.clone();
+        s.set_output(Box::new(io::sink()));
+        s.set_output_callback(Box::new(move |value| captured.borrow_mut().push(value)));
+        s.regfile[Simple::STACK_POINTER] = 0x1000;
+
+        s.raise_irq(4);
+        s.step().unwrap();
+        s.step().unwrap();
+        assert_eq!(s.interrupt_controller().mask, 0);
+        assert_eq!(s.interrupt_controller().pending, 1 << 4);
+        s.step().unwrap();
+        assert_eq!(s.interrupt_controller().in_service, 1 << 4);
+        s.set_step_limit(20);
+        assert_eq!(s.run(), StopReason::StepLimit);
+        assert_eq!(*lines.borrow(), vec![4]);
+
+        s.raise_irq(5);
+        s.raise_irq(2);
+        assert_eq!(s.interrupt_controller().next(), Some(2));
+        assert_eq!(s.run(), StopReason::StepLimit);
+        assert_eq!(*lines.borrow(), vec![4, 2, 5]);
+        assert_eq!(*s.interrupt_controller(), InterruptController {
+            mask: 0, pending: 0, in_service: 0,
+        });
+        assert_eq!(s.regfile[Simple::STACK_POINTER], 0x1000);
+    }
+
+    #[test]
+    fn illegal_handler() {
+        // mov r1, 5
+        // .word 0xf101 (double r1)
+        // inc r1
+        // .word 0xffff
+        let program = vec![0x81,0x05,0xf1,0x01,0x00,0x51,0xff,0xff];
+        let mut s = Simple::new();
+        s.load_program(program);
+        s.set_illegal_handler(Box::new(|s, word| {
+            if word & 0xff00 != 0xf100 {
+                return false;
+            }
+            let rd = (word & 0xf) as usize;
+            s.regfile[rd] = s.regfile[rd].wrapping_mul(2);
+            true
+        }));
+        assert_eq!(s.run(), StopReason::Fault(
+            EmuError::IllegalInstruction { addr: 6, word: 0xffff }));
+        assert_eq!(s.regfile[1], 11);
+    }
+
+    #[test]
+    fn load_acc32() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.load_program(assemble(&[LoadAcc32 { n: 0x12345678 }, Inc { rd: 1 }]));
+        assert_eq!(s.decode_at(0).to_string(), "mov acc, 0x12345678");
+        s.step().unwrap();
+        assert_eq!(s.ip(), 6);
+        assert_eq!(s.acc32(), 0x12345678);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 1);
+    }
+
+    #[test]
+    fn codes_convert_to_enums() {
+        assert_eq!(OpcodeClass::try_from(0x0), Ok(OpcodeClass::Alu));
+        assert_eq!(OpcodeClass::try_from(opcodes::MISC as u8), Ok(OpcodeClass::Misc));
+        assert_eq!(OpcodeClass::try_from(0xe), Ok(OpcodeClass::LoadDisplaced));
+        assert_eq!(OpcodeClass::try_from(0xf), Err(UnknownCode(0xf)));
+        assert_eq!(OpcodeClass::try_from(0x10), Err(UnknownCode(0x10)));
+
+        assert_eq!(Condition::try_from(9), Ok(Condition::Zero));
+        assert_eq!(Condition::try_from(13), Ok(Condition::Always));
+        assert_eq!(Condition::try_from(0), Err(UnknownCode(0)));
+        assert_eq!(Condition::try_from(14), Err(UnknownCode(14)));
+        for code in 1..=13 {
+            assert_eq!(Condition::try_from(code).map(|c| c as u8), Ok(code));
+        }
+        assert!(Condition::Zero.holds(alu::ZF));
+        assert!(!Condition::NotZero.holds(alu::ZF));
+    }
+
+    #[test]
+    fn run_with_io_echoes_input() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        // Copy bytes from the input port to the char port until EOF.
+        s.load_program(assemble(&[
+            MovI16 { rd: 1, n: Simple::INPUT_PORT as u16 },
+            MovI16 { rd: 2, n: Simple::CHAR_PORT as u16 },
+            Load { rd: 3, rs: 1, ro: 0 },
+            Inc { rd: 3 },
+            JumpRegister { rd: 3, nonzero: false, offset: 6 },
+            Dec { rd: 3 },
+            Store { rd: 2, rs: 3, ro: 0 },
+            JumpRelative { cond: 13, offset: -12 },
+            Halt,
+        ]));
+        let output = SharedBuffer::default();
+        let stop = s.run_with_io(io::Cursor::new(b"hello\n".to_vec()), output.clone());
+        assert_eq!(stop, StopReason::Halted);
+        assert_eq!(*output.0.borrow(), b"hello\n");
+    }
+
+    #[test]
+    fn with_ram_runs_full_image() {
+        use Instruction::*;
+        let mut image = vec![0u8; 65536].into_boxed_slice();
+        let code = assemble(&[
+            MovI16 { rd: 1, n: 0x2000 },
+            Load { rd: 2, rs: 1, ro: 0 },
+            Halt,
+        ]);
+        image[..code.len()].copy_from_slice(&code);
+        image[0x2000..0x2002].copy_from_slice(&[0xbe, 0xef]);
+        let mut s = Simple::with_ram(image);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[2], 0xbeef);
+    }
+
+    #[test]
+    fn moves_preserve_flags() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.regfile[2] = 1;
+        s.regfile[4] = 0x100;
+        s.load_program(assemble(&[
+            Alu { op: 11, rd: 1, rs: 2 },
+            Mov { rd: 3, rs: 2 },
+            Mov { rd: 40, rs: 2 },
+            MovI8 { rd: 3, n: 0 },
+            MovI16 { rd: 3, n: 0x8000 },
+            LoadAcc32 { n: 0 },
+            Store { rd: 4, rs: 2, ro: 0 },
+            Load { rd: 3, rs: 4, ro: 0 },
+            LoadDisplaced { rd: 3, rs: 4, disp: 2 },
+            Push { rd: 3 },
+            Pop { rd: 3 },
+            PushMultiple { mask: 0b1110 },
+            PopMultiple { mask: 0b1110 },
+            Alu { op: 1, rd: 1, rs: 2 },
+        ]));
+        s.step().unwrap();
+        let flags = s.flags();
+        assert_eq!(flags, alu::CF | alu::SF);
+        while s.decode_at(s.ip()) != (Alu { op: 1, rd: 1, rs: 2 }) {
+            s.step().unwrap();
+            assert_eq!(s.flags(), flags, "flags changed before {:#06x}", s.ip());
+        }
+        s.step().unwrap();
+        assert_eq!(s.flags(), alu::Flags::empty());
+    }
+
+    #[test]
+    fn nop() {
+        let mut s = Simple::with_halt_policy(HaltPolicy::RequireExplicitHalt);
+        s.load_program(vec![0xa0,0x02,0xa0,0x00]);
+        s.regfile[1] = 7;
+        s.regfile[Simple::FLAG_REGISTER] = alu::CF.bits();
+        let mut expected = s.save_registers();
+        expected[Simple::INSTRUCTION_POINTER] = 2;
+        assert_eq!(s.decode_at(0).to_string(), "nop");
+        assert_eq!(s.step(), Ok(true));
+        assert_eq!(s.save_registers(), expected);
+        assert_eq!(s.step(), Ok(false));
+    }
+
+    #[test]
+    fn push_pop_multiple() {
+        use Instruction::*;
+        let mask = 1 << 1 | 1 << 3 | 1 << 5;
+        let mut s = Simple::new();
+        s.load_program(assemble(&[
+            PushMultiple { mask },
+            MovI8 { rd: 1, n: 0 },
+            MovI8 { rd: 3, n: 0 },
+            MovI8 { rd: 5, n: 0 },
+            PopMultiple { mask },
+        ]));
+        assert_eq!(s.decode_at(0).to_string(), "pushm {r1, r3, r5}");
+        s.regfile[1] = 11;
+        s.regfile[3] = 33;
+        s.regfile[5] = 55;
+        s.regfile[Simple::STACK_POINTER] = 0x200;
+        s.step().unwrap();
+        assert_eq!(s.regfile[Simple::STACK_POINTER], 0x1fa);
+        assert_eq!([s.peek_16(0x1fa), s.peek_16(0x1fc), s.peek_16(0x1fe)], [55, 33, 11]);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!([s.regfile[1], s.regfile[3], s.regfile[5]], [11, 33, 55]);
+        assert_eq!(s.regfile[Simple::STACK_POINTER], 0x200);
+    }
+
+    #[test]
+    fn alignment_padding() {
+        use Instruction::*;
+        let mut program = assemble(&[MovI16 { rd: 1, n: 2 }, Inc { rd: 1 }]);
+        program.extend(Instruction::alignment_padding(program.len(), 16));
+        assert_eq!(program.len(), 16);
+        assert_eq!(Instruction::alignment_padding(16, 16), vec![]);
+        program.extend(assemble(&[Dec { rd: 1 }]));
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 2);
+        assert_eq!(s.ip(), 18);
+    }
+
+    #[test]
+    fn compare_jump_search() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.load_program(assemble(&[
+            MovI16 { rd: 1, n: 0x100 },
+            MovI8 { rd: 2, n: 7 },
+            // loop:
+            Load { rd: 3, rs: 1, ro: 0 },
+            CompareJump { cond: 9, ra: 3, rb: 2, offset: 6 },
+            AluImmediate { op: 1, rd: 1, n: 2 },
+            Inc { rd: 5 },
+            JumpRelative { cond: 13, offset: -12 },
+            // found:
+            Halt,
+        ]));
+        s.raw_write_range(0x100, &[0, 3, 0, 9, 0, 7, 0, 1]).unwrap();
+        assert_eq!(s.decode_at(8).to_string(), "cmpjz r3, r2, [ip + 6]");
+        assert_eq!(s.check_relative_jumps(0..20), vec![]);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[5], 2);
+        assert_eq!(s.ip(), 18);
+        assert!(!s.zf());
+    }
+
+    #[test]
+    fn compare_jump_less_equal() {
+        use Instruction::*;
+        let taken = |a: u16, b: u16| {
+            let mut s = Simple::new();
+            s.load_program(assemble(&[
+                CompareJump { cond: 8, ra: 1, rb: 2, offset: 2 },
+                MovI8 { rd: 3, n: 1 },
+                Halt,
+            ]));
+            s.regfile[1] = a;
+            s.regfile[2] = b;
+            assert_eq!(s.run(), StopReason::Halted);
+            s.regfile[3] == 0
+        };
+        assert!(taken(3, 5));
+        assert!(taken(5, 5));
+        assert!(!taken(5, 3));
+        assert!(taken(0xffff, 1));
+        assert!(!taken(1, 0xffff));
+        assert!(!Condition::LessEqual.holds(alu::Flags::empty()));
+        assert!(Condition::LessEqual.holds(alu::ZF));
+    }
+
+    struct CountingReader {
+        inner: io::Cursor<Vec<u8>>,
+        count: Rc<Cell<usize>>,
+    }
+
+    impl Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.count.set(self.count.get() + n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn streaming_memory() {
+        use Instruction::*;
+        let mut image = vec![0; 0x400];
+        let code = assemble(&[
+            MovI8 { rd: 1, n: 1 },
+            MovI8 { rd: 2, n: 2 },
+            MovI8 { rd: 3, n: 3 },
+        ]);
+        image[0xfc..0x102].copy_from_slice(&code);
+        let count = Rc::new(Cell::new(0));
+        let reader = CountingReader { inner: io::Cursor::new(image), count: count.clone() };
+        let mut s = Simple::with_memory(Box::new(StreamingMemory::new(reader)));
+        s.set_entry_point(0xfc);
+        assert_eq!(count.get(), 0);
+        s.step().unwrap();
+        assert_eq!(count.get(), 0x100);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!((s.regfile[1], s.regfile[2], s.regfile[3]), (1, 2, 3));
+        assert_eq!(count.get(), 0x200);
+    }
+
+    struct SparseMemory(HashMap<usize, u8>);
+
+    impl Memory for SparseMemory {
+        fn read(&self, addr: usize) -> u8 {
+            self.0.get(&addr).copied().unwrap_or(0)
+        }
+
+        fn write(&mut self, addr: usize, value: u8) {
+            self.0.insert(addr, value);
+        }
+
+        fn size(&self) -> usize {
+            65536
+        }
+    }
+
+    #[test]
+    fn sparse_memory() {
+        let program = vec![
+            0x88,0x0a,0x7d,0x24,0x7d,0x1a,0x88,0x64,
+            0x7d,0x1e,0x7d,0x14,0x81,0x00,0x92,0x00,
+            0x03,0xe8,0xb8,0x10,0x7d,0x16,0x7d,0x08,
+            0x00,0x51,0x0b,0x82,0x37,0xf4,0x00,0x00,
+            0x99,0x00,0xff,0x01,0x59,0x80,0xa0,0x01,
+            0x21,0x81,0xa0,0x01,0x2b,0x80,0x39,0x12,
+            0x89,0x00,0x8a,0x01,0x22,0x81,0x39,0x10,
+            0x01,0x9a,0x22,0x81,0x39,0x06,0x01,0xa9,
+            0x3d,0xf2,0xa0,0x01,0xb8,0x90,0xa0,0x01,
+            0xb8,0xa0,0xa0,0x01,
+        ];
+        let mut s = Simple::with_memory(Box::new(SparseMemory(HashMap::new())));
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[8], 1597);
+    }
+
+    #[test]
+    fn register_effects() {
+        // add r2, r1
+        assert_eq!(super::register_effects(0x0121), (vec![1, 2], vec![2, 17]));
+        // cmp r1, 0
+        assert_eq!(super::register_effects(0x2b10), (vec![1], vec![17]));
+        // push r3
+        assert_eq!(super::register_effects(0x0033), (vec![3, 15], vec![15]));
+        // jz [ip + 18]
+        assert_eq!(super::register_effects(0x3912), (vec![16, 17], vec![16]));
+        // ret
+        assert_eq!(super::register_effects(0xa001), (vec![15], vec![15, 16]));
+    }
+
+    #[test]
+    fn fixed_point_multiply() {
+        let mut s = Simple::new();
+        s.regfile[1] = 0x0180; // 1.5
+        s.regfile[2] = 0x0200; // 2.0
+        // fmul r1, r2
+        s.execute_word(0xa112, 0).unwrap();
+        assert_eq!(s.regfile[1], 0x0300);
+        assert_eq!(s.acc32(), 0x0003_0000);
+
+        s.regfile[1] = 0xfe80; // -1.5
+        s.execute_word(0xa112, 0).unwrap();
+        assert_eq!(s.regfile[1], 0xfd00);
+
+        s.regfile[1] = 0x8000; // 128.0
+        s.regfile[2] = 0x0100; // 1.0
+        // fmulu r1, r2
+        s.execute_word(0xa212, 0).unwrap();
+        assert_eq!(s.regfile[1], 0x8000);
+    }
+
+    #[test]
+    fn load_and_run() {
+        let regs = Simple::load_and_run(&[0x81,0x0a,0x82,0x0b,0x01,0x21], 100).unwrap();
+        assert_eq!(regs[2], 21);
+        let err = Simple::load_and_run(&[0xff,0xff], 100).unwrap_err();
+        assert_eq!(err, EmuError::IllegalInstruction { addr: 0, word: 0xffff });
+    }
+
+    #[test]
+    fn uninitialized_register() {
+        // mov r1, 10
+        // add r2, r1
+        let program = vec![0x81,0x0a,0x01,0x21];
+        let mut s = Simple::new();
+        s.set_uninitialized_check(true);
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Fault(EmuError::UninitializedRegister { reg: 2 }));
+        assert_eq!(s.instruction_pointer(), 2);
+
+        let program = vec![0x81,0x0a,0x82,0x0b,0x01,0x21];
+        let mut s = Simple::new();
+        s.set_uninitialized_check(true);
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[2], 21);
+    }
+
+    #[test]
+    fn wait_states() {
+        let cycles_at = |addr: u16| {
+            let program = assemble(&[
+                Instruction::MovI16 { rd: 2, n: addr },
+                Instruction::Load { rd: 3, rs: 2, ro: 0 },
+                Instruction::Store { rd: 2, rs: 3, ro: 0 },
+                Instruction::Halt,
+            ]);
+            let mut s = Simple::new();
+            s.load_program(program);
+            s.add_wait_states(0x8000..0x9000, 3);
+            assert_eq!(s.run(), StopReason::Halted);
+            s.cycles()
+        };
+        assert_eq!(cycles_at(0x100), 3);
+        assert_eq!(cycles_at(0x8000), 3 + 2 * 3);
+    }
+
+    #[test]
+    fn wait_states_within_emulate_budget() {
+        use Instruction::*;
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.load_program(assemble(&[
+            MovI16 { rd: 1, n: 0x8000 },
+            MovI8 { rd: 2, n: 10 },
+            Store { rd: 1, rs: 2, ro: 0 },
+            Dec { rd: 2 },
+            JumpRegister { rd: 2, nonzero: true, offset: -6 },
+            Halt,
+        ]));
+        s.add_wait_states(0x8000..0x9000, 5);
+        let mut total = 0;
+        while !s.is_halted() {
+            let emulated = s.emulate(4);
+            assert!(emulated.cycles_used <= 4);
+            total += emulated.cycles_used;
+        }
+        assert_eq!(s.cycles(), 2 + 10 * (3 + 5));
+        assert_eq!(total + s.overrun, s.cycles());
+    }
+
+    #[test]
+    fn read_cycles() {
+        // rdcyc r1
+        // mov r3, 5
+        // loop:
+        // dec r3
+        // jrnz r3, loop
+        // rdcyc r2
+        let program = vec![0xa3,0x10,0x83,0x05,0x00,0x63,0xd3,0xfc,0xa3,0x20];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 0);
+        assert_eq!(s.regfile[2] - s.regfile[1], 1 + 1 + 5 * 2);
+        assert_eq!(s.cycles(), 13);
+    }
+
+    #[test]
+    fn step_after_halt() {
+        // mov r1, 10
+        // hlt
+        // inc r1
+        let program = vec![0x81,0x0a,0xa0,0x00,0x00,0x51];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert!(s.is_halted());
+        let regfile = s.regfile;
+        assert_eq!(s.step(), Ok(false));
+        assert_eq!(s.step(), Ok(false));
+        assert_eq!(s.regfile, regfile);
+        assert_eq!(s.cycles(), 1);
+
+        s.set_instruction_pointer(4);
+        assert!(!s.is_halted());
+        assert_eq!(s.step(), Ok(true));
+        assert_eq!(s.regfile[1], 11);
+    }
+
+    #[test]
+    fn framebuffer() {
+        // mov r1, 0xf000
+        // mov r2, 0x8001
+        // mov r3, 8
+        // mov [r1 + r0], r2
+        // mov [r1 + r3], r2
+        // hlt
+        let program = vec![
+            0x91,0x00,0xf0,0x00,0x92,0x00,0x80,0x01,0x83,0x08,
+            0x51,0x20,0x51,0x23,0xa0,0x00,
+        ];
+        let mut s = Simple::new();
+        s.load_program(program);
+        assert_eq!(s.run(), StopReason::Halted);
+        let art = s.framebuffer_ascii();
+        let lines: Vec<&str> = art.lines().collect();
+        let pattern = format!("#{}#{}", ".".repeat(14), ".".repeat(48));
+        assert_eq!(lines.len(), Simple::FRAMEBUFFER_HEIGHT);
+        assert_eq!(lines[0], pattern);
+        assert_eq!(lines[1], pattern);
+        assert_eq!(lines[2], ".".repeat(64));
     }
 
-    pub fn run(&mut self) {
-        while self.step() {}
+    #[test]
+    fn overflow_accessors() {
+        let mut s = Simple::new();
+        s.regfile[1] = 0xffff;
+        s.regfile[2] = 1;
+        // add r1, r2
+        s.execute_word(0x0112, 0).unwrap();
+        assert_eq!(s.regfile[1], 0);
+        assert!(s.last_carried());
+        assert!(!s.last_signed_overflowed());
+        assert!(s.last_overflowed());
+
+        s.regfile[1] = 0x7fff;
+        s.execute_word(0x0112, 0).unwrap();
+        assert!(!s.last_carried());
+        assert!(s.last_signed_overflowed());
+        assert!(s.last_overflowed());
+        assert_eq!(s.last_carried(), s.cf());
+        assert_eq!(s.last_signed_overflowed(), s.of());
+
+        s.regfile[1] = 1;
+        s.execute_word(0x0112, 0).unwrap();
+        assert!(!s.last_overflowed());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn trace_fib_to_file() {
+        let path = std::env::temp_dir()
+            .join(format!("simple_emu_trace_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut s = Simple::new();
+        s.load_program(FIB_PROGRAM.to_vec());
+        s.regfile[1] = 11;
+        s.trace_to_file(&path).unwrap();
+        let mut executed = 1;
+        while s.step().unwrap() {
+            executed += 1;
+        }
+        assert_eq!(s.regfile[1], 55);
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(log.lines().count(), executed);
+        assert!(log.lines().next().unwrap().contains("ip=0002"));
+    }
 
     #[test]
-    fn add_program() {
-        // mov r1, 10
-        // mov r2, 11
-        // add r2, r1
-        let program = vec![0x81,0x0a,0x82,0x0b,0x01,0x21];
+    fn signed_register_dump() {
         let mut s = Simple::new();
-        s.load_program(program);
-        s.run();
-        assert_eq!(s.regfile[2], 21);
+        s.regfile[2] = 0xfffe;
+        s.regfile[3] = 7;
+        assert!(s.dump_registers().contains("r2=fffe r3=0007"));
+        s.set_signed_registers(true);
+        assert!(s.dump_registers().starts_with("r0=0 r1=0 r2=-2 r3=7 "));
     }
 
     #[test]
-    fn jmp_program() {
-        // mov r1, 1
+    fn jump_relative_immediate() {
         // jmp [ip + 2]
+        // mov r1, 1
         // mov r2, 2
-        let program = vec![0x81,0x01,0x3d,0x02,0x82,0x02];
+        let program = vec![0x1d,0x03,0x00,0x02,0x81,0x01,0x82,0x02];
         let mut s = Simple::new();
         s.load_program(program);
-        s.run();
+        assert_eq!(s.decode_at(0).to_string(), "jmp [ip + 2]");
+        assert_eq!(s.check_relative_jumps(0..8), vec![]);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 0);
+        assert_eq!(s.regfile[2], 2);
+    }
+
+    #[test]
+    fn jump_double_indirect() {
+        let mut s = Simple::new();
+        s.raw_write_range(0x20, &[0x00, 0x30]).unwrap();
+        s.raw_write_range(0x30, &[0x00, 0x0a]).unwrap();
+        s.regfile[3] = 0x20;
+        // jmp [[r3]]
+        assert_eq!(Instruction::decode(0x1d34, 0).to_string(), "jmp [[r3]]");
+        s.execute_word(0x1d34, 0).unwrap();
+        assert_eq!(s.ip(), 0x0a);
+    }
+
+    #[test]
+    fn jump_reserved_typ() {
+        let mut s = Simple::new();
+        s.load_program(vec![0x1d,0x05]);
+        assert_eq!(s.step(),
+            Err(EmuError::IllegalInstruction { addr: 0, word: 0x1d05 }));
+    }
+
+    #[test]
+    fn ip_wrap_detection() {
+        // mov r1, 1
+        let mut s = Simple::new();
+        s.raw_write_range(0xfffe, &[0x81, 0x01]).unwrap();
+        s.set_instruction_pointer(0xfffe);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 1);
+
+        let mut s = Simple::new();
+        s.raw_write_range(0xfffe, &[0x81, 0x01]).unwrap();
+        s.set_instruction_pointer(0xfffe);
+        s.set_ip_wrap_detection(true);
+        assert_eq!(s.run(), StopReason::InstructionPointerWrap);
         assert_eq!(s.regfile[1], 1);
-        assert_eq!(s.regfile[2], 0);
     }
 
     #[test]
-    fn fib_program() {
-        let program = vec![
-            0x2b,0x10,0x39,0x12,0x82,0x00,0x83,0x01,
-            0x22,0x11,0x39,0x0c,0x01,0x23,0x22,0x11,
-            0x39,0x0a,0x01,0x32,0x3d,0xf2,0x00,0x00,
-            0xb1,0x20,0x00,0x00,0xb1,0x30,0x00,0x00,
-        ];
+    fn load_displaced() {
         let mut s = Simple::new();
-        s.load_program(program);
-        s.regfile[1] = 11;
-        s.run();
-        assert_eq!(s.regfile[1], 55);
+        // struct at 0x40: { 0x1111, 0x2222, 0x3333 }
+        s.raw_write_range(0x40, &[0x11, 0x11, 0x22, 0x22, 0x33, 0x33]).unwrap();
+        s.regfile[2] = 0x40;
+        // mov r1, [r2 + 4]
+        assert_eq!(Instruction::decode(0xe122, 0).to_string(), "mov r1, [r2 + 4]");
+        s.execute_word(0xe122, 0).unwrap();
+        assert_eq!(s.regfile[1], 0x3333);
+        // mov r3, [r4 - 2]
+        s.regfile[4] = 0x44;
+        assert_eq!(Instruction::decode(0xe34f, 0).to_string(), "mov r3, [r4 - 2]");
+        s.execute_word(0xe34f, 0).unwrap();
+        assert_eq!(s.regfile[3], 0x2222);
     }
 
     #[test]
-    fn inc_program() {
-        let program = vec![0x00,0x51,0x00,0x51,0x00,0x51];
+    fn sanity_check_stack_pointer() {
+        // push r1
+        // push r1
         let mut s = Simple::new();
-        s.load_program(program);
-        s.run();
-        assert_eq!(s.regfile[1], 3);
+        s.load_program(vec![0x00,0x31,0x00,0x31]);
+        s.set_sanity_checks(true);
+        s.set_stack_region(0x1fe..0x200);
+        s.regfile[Simple::STACK_POINTER] = 0x200;
+        s.step().unwrap();
+        assert_eq!(s.step(), Err(EmuError::InvariantViolation(
+            Invariant::StackPointerOutOfRange { sp: 0x1fc })));
     }
 
     #[test]
-    fn stack_program() {
-        let program = vec![
-            0x81,0xff,0x00,0x31,0x00,0x31,0x00,0x31,
-            0x00,0x42,0x00,0x43,0x00,0x44,0x00,0x45,
-        ];
+    fn sanity_check_decodable() {
+        // mov r1, 1
+        // .word 0xffff
         let mut s = Simple::new();
-        s.load_program(program);
-        s.run();
-        assert_eq!(s.regfile[1], 255);
-        assert_eq!(s.regfile[2], 255);
-        assert_eq!(s.regfile[3], 255);
-        assert_eq!(s.regfile[4], 255);
-        assert_ne!(s.regfile[5], 255);
-        assert_eq!(s.regfile[15], 2);
+        s.load_program(vec![0x81,0x01,0xff,0xff]);
+        s.set_sanity_checks(true);
+        let err = s.step().unwrap_err();
+        assert_eq!(err, EmuError::InvariantViolation(
+            Invariant::UndecodableInstruction { addr: 2, word: 0xffff }));
+        assert_eq!(err.to_string(),
+            "invariant violated: ip points at undecodable word 0xffff at 0x0002");
     }
 
     #[test]
-    fn call_program() {
-        let program = vec![
-            0x88,0x0a,0x7d,0x24,0x7d,0x1a,0x88,0x64,
-            0x7d,0x1e,0x7d,0x14,0x81,0x00,0x92,0x00,
-            0x03,0xe8,0xb8,0x10,0x7d,0x16,0x7d,0x08,
-            0x00,0x51,0x0b,0x82,0x37,0xf4,0x00,0x00,
-            0x99,0x00,0xff,0x01,0x59,0x80,0xa0,0x01,
-            0x21,0x81,0xa0,0x01,0x2b,0x80,0x39,0x12,
-            0x89,0x00,0x8a,0x01,0x22,0x81,0x39,0x10,
-            0x01,0x9a,0x22,0x81,0x39,0x06,0x01,0xa9,
-            0x3d,0xf2,0xa0,0x01,0xb8,0x90,0xa0,0x01,
-            0xb8,0xa0,0xa0,0x01,
+    fn encode_round_trip() {
+        use opcodes::*;
+        // add r2, r1
+        let word = ALU << CLASS_SHIFT | 1 << 8 | 2 << 4 | 1;
+        assert_eq!(Instruction::decode(word, 0).to_string(), "add r2, r1");
+        assert_eq!(Instruction::decode(word, 0).encode(), vec![word]);
+
+        let instructions = [
+            Instruction::Zero,
+            Instruction::Pop { rd: 3 },
+            Instruction::LoadFlags { rd: 5 },
+            Instruction::StoreFlags { rd: 6 },
+            Instruction::SNeg { rd: 4 },
+            Instruction::Alu { op: 11, rd: 1, rs: 2 },
+            Instruction::Jump { cond: 13, target: JumpTarget::Indirect(5) },
+            Instruction::Jump { cond: 9, target: JumpTarget::Relative(-8) },
+            Instruction::Call { cond: 13, target: JumpTarget::Immediate(0x100) },
+            Instruction::AluImmediate { op: 2, rd: 7, n: 9 },
+            Instruction::JumpRelative { cond: 10, offset: -14 },
+            Instruction::CallRelative { cond: 13, offset: 6 },
+            Instruction::Load { rd: 1, rs: 2, ro: 3 },
+            Instruction::Store { rd: 4, rs: 5, ro: 6 },
+            Instruction::LoadDisplaced { rd: 1, rs: 2, disp: -4 },
+            Instruction::MovI8 { rd: 8, n: 200 },
+            Instruction::MovI16 { rd: 9, n: 0xbeef },
+            Instruction::Cas { ra: 1, re: 2, rn: 3 },
+            Instruction::JumpRegister { rd: 3, nonzero: true, offset: -4 },
+            Instruction::FixedMul { rd: 1, rs: 2, signed: false },
+            Instruction::ReadCycles { rd: 6 },
+            Instruction::CompareJump { cond: 10, ra: 1, rb: 2, offset: -0x800 },
+            Instruction::PushMultiple { mask: 0b1000_0000_0010_1010 },
+            Instruction::PopMultiple { mask: 0xffff },
+            Instruction::Nop,
+            Instruction::Compressed(true),
+            Instruction::Compressed(false),
+            Instruction::Halt,
+            Instruction::Ret,
+            Instruction::Mov { rd: 18, rs: 33 },
+            Instruction::LoadAcc32 { n: 0xdeadbeef },
+            Instruction::StoreByte { rd: 4, rs: 9 },
+            Instruction::CompareImmediate { rd: 7, n: 0x8001 },
+            Instruction::HaltWithCode { rd: 12 },
+            Instruction::Illegal(0xffff),
         ];
+        for instruction in instructions.iter() {
+            let words = instruction.encode();
+            assert_eq!(words.len() * 2, instruction.size());
+            let next = words.get(1).copied().unwrap_or(0);
+            let next2 = words.get(2).copied().unwrap_or(0);
+            let decoded = Instruction::decode_wide(words[0], next, next2);
+            assert_eq!(decoded, *instruction);
+            assert_eq!(decoded.to_string(), instruction.to_string());
+        }
+    }
+
+    #[test]
+    fn output_callback() {
+        let values = Rc::new(RefCell::new(Vec::new()));
+        let captured = values.clone();
+        let mut s = Simple::new();
+        s.set_trace(false);
+        s.set_output(Box::new(io::sink()));
+        s.set_output_callback(Box::new(move |value| captured.borrow_mut().push(value)));
+        s.regfile[1] = Simple::CONSOLE_PORT as u16;
+        s.regfile[2] = 7;
+        s.regfile[3] = 0x1234;
+        // mov [r1 + r0], r2
+        s.execute_word(0x5120, 0).unwrap();
+        // mov [r1 + r0], r3
+        s.execute_word(0x5130, 0).unwrap();
+        assert_eq!(*values.borrow(), vec![7, 0x1234]);
+    }
+
+    #[test]
+    fn load_program_length() {
+        let mut s = Simple::new();
+        assert_eq!(s.load_program(COUNT_LOOP.to_vec()), COUNT_LOOP.len());
+        assert_eq!(s.load_program_at(0x100, vec![0x81, 0x05], false), 2);
+        assert_eq!(s.load_program(vec![]), 0);
+        assert_eq!(s.load_program_at(0xfffe, vec![0x81, 0x05, 0x00, 0x51], false), 4);
+        assert_eq!(s.memory()[0xfffe..], [0x81, 0x05]);
+        assert_eq!(s.memory()[..2], [0x00, 0x51]);
+    }
+
+    #[test]
+    fn entry_point() {
+        // mov r1, 5
+        // inc r1
+        let program = vec![0x81,0x05,0x00,0x51];
+        let mut s = Simple::new();
+        s.load_program_at(0x0100, program.clone(), false);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 0);
+
+        let mut s = Simple::new();
+        s.load_program_at(0x0100, program, true);
+        assert_eq!(s.instruction_pointer(), 0x0100);
+        assert_eq!(s.run(), StopReason::Halted);
+        assert_eq!(s.regfile[1], 6);
+        assert_eq!(s.instruction_pointer(), 0x0104);
+    }
+
+    #[test]
+    fn fib_listing() {
+        let path = std::env::temp_dir()
+            .join(format!("simple_emu_listing_{}.lst", std::process::id()));
+        let mut s = Simple::new();
+        s.load_program(FIB_PROGRAM.to_vec());
+        s.write_listing(&path, 0, 32).unwrap();
+        let listing = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(listing.lines().count(), 16);
+        assert!(listing.lines().any(|line| line == "0014  3d f2        jmp [ip - 14]"));
+    }
+
+    #[test]
+    fn raw_write_range_bypasses_mmio() {
+        let mut s = Simple::new();
+        s.raw_write_range(0xFF00, &[0x12, 0x34, 0x56, 0x78]).unwrap();
+        assert_eq!(s.memory()[0xFF00..0xFF04], [0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(s.read_16(0xFF01), Ok(0x3456));
+    }
+
+    #[test]
+    fn strict_alignment() {
+        let mut s = Simple::new();
+        s.raw_write_range(0x100, &[0x12, 0x34, 0x56]).unwrap();
+        assert_eq!(s.read_16(0x101), Ok(0x3456));
+        s.set_strict_alignment(true);
+        assert_eq!(s.read_16(0x100), Ok(0x1234));
+        assert_eq!(s.read_16(0x101), Err(EmuError::UnalignedAccess { addr: 0x101 }));
+        assert_eq!(s.write_16(0x103, 0), Err(EmuError::UnalignedAccess { addr: 0x103 }));
+    }
+
+    #[test]
+    fn misaligned_fetch() {
+        // mov r1, 5
+        // jmp r1
+        let program = vec![0x81,0x05,0x1d,0x10];
+        let mut s = Simple::new();
+        s.load_program(program.clone());
+        s.set_strict_alignment(true);
+        assert_eq!(s.run(), StopReason::Fault(EmuError::MisalignedFetch { ip: 5 }));
+
         let mut s = Simple::new();
         s.load_program(program);
-        s.run();
-        assert_eq!(s.regfile[8], 1597);
+        assert_eq!(s.run(), StopReason::Halted);
     }
 }
 
-mod alu {
-    // TODO: bitflags!?
-    type Flags = u16;
-    pub const ZF: u16 = 0b0001;
-    pub const CF: u16 = 0b0010;
-    pub const OF: u16 = 0b0100;
-    pub const SF: u16 = 0b1000;
-    pub const EF: u16 = 0b100_0000;
+/// The arithmetic/logic unit, usable on its own for custom decoders or for
+/// checking flag behavior.
+pub mod alu {
+    use std::fmt;
+    use std::ops::{BitAnd, BitOr, BitOrAssign};
+
+    /// The bits of the flag register. Bits without a name are kept as-is,
+    /// since programs can write anything to the register.
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct Flags(u16);
+
+    impl Flags {
+        pub const ZF: Flags = Flags(0b0001);
+        pub const CF: Flags = Flags(0b0010);
+        pub const OF: Flags = Flags(0b0100);
+        pub const SF: Flags = Flags(0b1000);
+        pub const EF: Flags = Flags(0b100_0000);
+        const NAMED: [(&'static str, Flags); 5] = [
+            ("ZF", Flags::ZF),
+            ("CF", Flags::CF),
+            ("OF", Flags::OF),
+            ("SF", Flags::SF),
+            ("EF", Flags::EF),
+        ];
+
+        pub const fn empty() -> Flags {
+            Flags(0)
+        }
+
+        pub const fn all() -> Flags {
+            Flags(0b100_1111)
+        }
+
+        pub const fn bits(self) -> u16 {
+            self.0
+        }
+
+        /// `None` if `bits` has any bit set that isn't a named flag.
+        pub const fn from_bits(bits: u16) -> Option<Flags> {
+            if bits & !Flags::all().0 == 0 {
+                Some(Flags(bits))
+            } else {
+                None
+            }
+        }
+
+        pub const fn from_bits_truncate(bits: u16) -> Flags {
+            Flags(bits & Flags::all().0)
+        }
+
+        pub const fn from_bits_retain(bits: u16) -> Flags {
+            Flags(bits)
+        }
+
+        pub const fn is_empty(self) -> bool {
+            self.0 == 0
+        }
+
+        pub const fn contains(self, other: Flags) -> bool {
+            self.0 & other.0 == other.0
+        }
+
+        pub fn insert(&mut self, other: Flags) {
+            self.0 |= other.0;
+        }
+
+        pub fn remove(&mut self, other: Flags) {
+            self.0 &= !other.0;
+        }
+
+        pub fn set(&mut self, other: Flags, value: bool) {
+            if value {
+                self.insert(other);
+            } else {
+                self.remove(other);
+            }
+        }
+    }
+
+    impl BitOr for Flags {
+        type Output = Flags;
+
+        fn bitor(self, other: Flags) -> Flags {
+            Flags(self.0 | other.0)
+        }
+    }
+
+    impl BitOrAssign for Flags {
+        fn bitor_assign(&mut self, other: Flags) {
+            self.insert(other);
+        }
+    }
+
+    impl BitAnd for Flags {
+        type Output = Flags;
+
+        fn bitand(self, other: Flags) -> Flags {
+            Flags(self.0 & other.0)
+        }
+    }
+
+    impl From<Flags> for u16 {
+        fn from(flags: Flags) -> u16 {
+            flags.0
+        }
+    }
+
+    impl From<u16> for Flags {
+        fn from(bits: u16) -> Flags {
+            Flags::from_bits_retain(bits)
+        }
+    }
+
+    /// Formats as the set flag names, e.g. `ZF | CF`, with any unnamed bits
+    /// in hex; `(empty)` if nothing is set.
+    impl fmt::Debug for Flags {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            if self.is_empty() {
+                return write!(f, "(empty)");
+            }
+            let mut parts: Vec<String> = Flags::NAMED.iter()
+                .filter(|(_, flag)| self.contains(*flag))
+                .map(|(name, _)| name.to_string())
+                .collect();
+            let unnamed = self.0 & !Flags::all().0;
+            if unnamed != 0 {
+                parts.push(format!("{:#x}", unnamed));
+            }
+            write!(f, "{}", parts.join(" | "))
+        }
+    }
+
+    pub const ZF: Flags = Flags::ZF;
+    pub const CF: Flags = Flags::CF;
+    pub const OF: Flags = Flags::OF;
+    pub const SF: Flags = Flags::SF;
+    pub const EF: Flags = Flags::EF;
 
-    type AluResult = (u16, Flags);
+    pub type AluResult = (u16, Flags);
     type AluOp = fn(u16, u16, Flags) -> AluResult;
 
+    /// Run ALU operation `op` on `a` and `b`, returning the result and the
+    /// new flags. Undefined operations return `(0, EF)`.
+    ///
+    /// ```
+    /// use simple_emu::alu::{self, Flags};
+    ///
+    /// // op 1 is add
+    /// assert_eq!(alu::alu(1, 2, 3, Flags::empty()), (5, Flags::empty()));
+    /// ```
     pub fn alu(op: usize, a: u16, b: u16, flags: Flags) -> AluResult {
         if let Some(op) = dispatch_op(op) {
             op(a, b, flags)
@@ -391,6 +5943,27 @@ mod alu {
         }
     }
 
+    pub fn mnemonic(op: usize) -> Option<&'static str> {
+        match op {
+            1 => Some("add"),
+            2 => Some("sub"),
+            3 => Some("or"),
+            4 => Some("nor"),
+            5 => Some("and"),
+            6 => Some("nand"),
+            7 => Some("xor"),
+            8 => Some("xnor"),
+            9 => Some("adc"),
+            10 => Some("sbb"),
+            11 => Some("cmp"),
+            12 => Some("umin"),
+            13 => Some("umax"),
+            14 => Some("smin"),
+            15 => Some("smax"),
+            _ => None,
+        }
+    }
+
     fn dispatch_op(op: usize) -> Option<AluOp> {
         match op {
             1 => Some(add),
@@ -404,6 +5977,10 @@ mod alu {
             9 => Some(adc),
             10 => Some(sbb),
             11 => Some(cmp),
+            12 => Some(umin),
+            13 => Some(umax),
+            14 => Some(smin),
+            15 => Some(smax),
             _ => None,
         }
     }
@@ -412,20 +5989,31 @@ mod alu {
         let zf = c == 0;
         let sf = c & 0x8000 > 0;
         let of = !cf && sf;
-        zf as u16 +
-            ((cf as u16) << 1) +
-            ((of as u16) << 2) +
-            ((sf as u16) << 3)
+        let mut flags = Flags::empty();
+        flags.set(ZF, zf);
+        flags.set(CF, cf);
+        flags.set(OF, of);
+        flags.set(SF, sf);
+        flags
+    }
+
+    /// Flags for arithmetic, where OF is signed overflow.
+    fn arith_flags(c: u16, cf: bool, of: bool) -> Flags {
+        let mut flags = signed_flags(c, of);
+        flags.set(CF, cf);
+        flags
     }
 
     fn add(a: u16, b: u16, _f: Flags) -> AluResult {
         let (c, cf) = a.overflowing_add(b);
-        (c, flags(c, cf))
+        let (_, of) = (a as i16).overflowing_add(b as i16);
+        (c, arith_flags(c, cf, of))
     }
 
     fn sub(a: u16, b: u16, _f: Flags) -> AluResult {
         let (c, cf) = a.overflowing_sub(b);
-        (c, flags(c, cf))
+        let (_, of) = (a as i16).overflowing_sub(b as i16);
+        (c, arith_flags(c, cf, of))
     }
 
     fn or(a: u16, b: u16, _f: Flags) -> AluResult {
@@ -459,26 +6047,74 @@ mod alu {
     }
 
     fn cf(f: Flags) -> u16 {
-        if f & CF > 0 { 1 } else { 0 }
+        f.contains(CF) as u16
+    }
+
+    fn fits_i16(n: i32) -> bool {
+        (i16::MIN as i32..=i16::MAX as i32).contains(&n)
     }
 
+    // The carry in takes part in both the carry out and the overflow, so
+    // these are computed wide rather than as two 16-bit steps.
     fn adc(a: u16, b: u16, f: Flags) -> AluResult {
-        // TODO: are these flags right if a + CF overflows?
-        let a = a.wrapping_add(cf(f));
-        let (c, cf) = a.overflowing_add(b);
-        (c, flags(c, cf))
+        let carry = cf(f);
+        let wide = a as u32 + b as u32 + carry as u32;
+        let signed = a as i16 as i32 + b as i16 as i32 + carry as i32;
+        let c = wide as u16;
+        (c, arith_flags(c, wide > 0xFFFF, !fits_i16(signed)))
     }
 
     fn sbb(a: u16, b: u16, f: Flags) -> AluResult {
-        // TODO: are these flags right if a - CF overflows?
-        let a = a.wrapping_sub(cf(f));
-        let (c, cf) = a.overflowing_sub(b);
-        (c, flags(c, cf))
+        let borrow = cf(f);
+        let signed = a as i16 as i32 - b as i16 as i32 - borrow as i32;
+        let c = a.wrapping_sub(b).wrapping_sub(borrow);
+        let cf = (a as u32) < b as u32 + borrow as u32;
+        (c, arith_flags(c, cf, !fits_i16(signed)))
     }
 
-    fn cmp(a: u16, b: u16, _f: Flags) -> AluResult {
-        let (c, cf) = a.overflowing_sub(b);
-        (a, flags(c, cf))
+    fn cmp(a: u16, b: u16, f: Flags) -> AluResult {
+        (a, sub(a, b, f).1)
+    }
+
+    fn signed_flags(c: u16, of: bool) -> Flags {
+        let mut flags = Flags::empty();
+        flags.set(ZF, c == 0);
+        flags.set(OF, of);
+        flags.set(SF, c & 0x8000 > 0);
+        flags
+    }
+
+    /// Absolute value of `a` as an `i16`. `0x8000` has no positive
+    /// counterpart, so it's returned unchanged with OF set.
+    pub fn abs(a: u16) -> AluResult {
+        let (c, of) = (a as i16).overflowing_abs();
+        (c as u16, signed_flags(c as u16, of))
+    }
+
+    /// Two's complement negation of `a`, setting OF for `0x8000`.
+    pub fn negate(a: u16) -> AluResult {
+        let (c, of) = (a as i16).overflowing_neg();
+        (c as u16, signed_flags(c as u16, of))
+    }
+
+    fn umin(a: u16, b: u16, _f: Flags) -> AluResult {
+        let c = a.min(b);
+        (c, flags(c, false))
+    }
+
+    fn umax(a: u16, b: u16, _f: Flags) -> AluResult {
+        let c = a.max(b);
+        (c, flags(c, false))
+    }
+
+    fn smin(a: u16, b: u16, _f: Flags) -> AluResult {
+        let c = (a as i16).min(b as i16) as u16;
+        (c, flags(c, false))
+    }
+
+    fn smax(a: u16, b: u16, _f: Flags) -> AluResult {
+        let c = (a as i16).max(b as i16) as u16;
+        (c, flags(c, false))
     }
 
     #[cfg(test)]
@@ -487,98 +6123,285 @@ mod alu {
 
         #[test]
         fn simple_addition() {
-            assert_eq!(add(5, 10, 0), (15, 0))
+            assert_eq!(add(5, 10, Flags::empty()), (15, Flags::empty()))
         }
 
         #[test]
         fn simple_subtraction() {
-            assert_eq!(sub(10, 5, 0), (5, 0))
+            assert_eq!(sub(10, 5, Flags::empty()), (5, Flags::empty()))
         }
 
         #[test]
         fn negative_subtraction() {
-            assert_eq!(sub(5, 10, 0), (-5i16 as u16, SF | CF));
+            assert_eq!(sub(5, 10, Flags::empty()), (-5i16 as u16, SF | CF));
         }
 
         #[test]
         fn simple_or() {
-            assert_eq!(or(5, 10, 0), (15, 0))
+            assert_eq!(or(5, 10, Flags::empty()), (15, Flags::empty()))
         }
 
         #[test]
         fn simple_nor() {
-            assert_eq!(nor(3, 5, 0), (!7u16, SF | OF))
+            assert_eq!(nor(3, 5, Flags::empty()), (!7u16, SF | OF))
         }
 
         #[test]
         fn simple_and() {
-            assert_eq!(and(3, 5, 0), (1, 0))
+            assert_eq!(and(3, 5, Flags::empty()), (1, Flags::empty()))
         }
 
         #[test]
         fn simple_nand() {
-            assert_eq!(nand(3, 5, 0), (!1u16, SF | OF))
+            assert_eq!(nand(3, 5, Flags::empty()), (!1u16, SF | OF))
         }
 
         #[test]
         fn simple_xor() {
-            assert_eq!(xor(3, 5, 0), (6, 0))
+            assert_eq!(xor(3, 5, Flags::empty()), (6, Flags::empty()))
         }
 
         #[test]
         fn simple_xnor() {
-            assert_eq!(xnor(3, 5, 0), (!6u16, SF | OF))
+            assert_eq!(xnor(3, 5, Flags::empty()), (!6u16, SF | OF))
         }
 
         #[test]
         fn simple_adc() {
-            assert_eq!(adc(3, 5, 0), (8, 0))
+            assert_eq!(adc(3, 5, Flags::empty()), (8, Flags::empty()))
         }
 
         #[test]
         fn simple_sbb() {
-            assert_eq!(sbb(5, 3, 0), (2, 0))
+            assert_eq!(sbb(5, 3, Flags::empty()), (2, Flags::empty()))
         }
 
         #[test]
         fn carrying_adc() {
-            assert_eq!(adc(3, 5, 0b0010), (9, 0))
+            assert_eq!(adc(3, 5, CF), (9, Flags::empty()))
         }
 
         #[test]
         fn borrowing_sbb() {
-            assert_eq!(sbb(5, 3, 0b0010), (1, 0))
+            assert_eq!(sbb(5, 3, CF), (1, Flags::empty()))
         }
 
         #[test]
         fn overflowing_carrying_adc() {
-            assert_eq!(adc(0xFFFE, 1, 0b0010), (0, CF | ZF))
+            assert_eq!(adc(0xFFFE, 1, CF), (0, CF | ZF))
         }
 
         #[test]
         fn overflowing_borrowing_sbb() {
-            assert_eq!(sbb(0x8001, 1, 0b0010), (0x7FFF, 0))
+            assert_eq!(sbb(0x8001, 1, CF), (0x7FFF, OF))
         }
 
         #[test]
         fn simple_cmp_gt() {
-            assert_eq!(cmp(5, 3, 0), (5, 0))
+            assert_eq!(cmp(5, 3, Flags::empty()), (5, Flags::empty()))
         }
 
         #[test]
         fn simple_cmp_eq() {
-            assert_eq!(cmp(5, 5, 0), (5, ZF))
+            assert_eq!(cmp(5, 5, Flags::empty()), (5, ZF))
         }
 
         #[test]
         fn simple_cmp_lt() {
-            assert_eq!(cmp(5, 8, 0), (5, SF | CF))
+            assert_eq!(cmp(5, 8, Flags::empty()), (5, SF | CF))
+        }
+
+        #[test]
+        fn simple_umin() {
+            assert_eq!(umin(0xFFFF, 1, Flags::empty()), (1, Flags::empty()))
+        }
+
+        #[test]
+        fn simple_umax() {
+            assert_eq!(umax(0xFFFF, 1, Flags::empty()), (0xFFFF, SF | OF))
+        }
+
+        #[test]
+        fn simple_smin() {
+            assert_eq!(smin(-1i16 as u16, 1, Flags::empty()), (0xFFFF, SF | OF))
+        }
+
+        #[test]
+        fn simple_smax() {
+            assert_eq!(smax(-1i16 as u16, 1, Flags::empty()), (1, Flags::empty()))
+        }
+
+        #[test]
+        fn zero_min() {
+            assert_eq!(umin(0, 7, Flags::empty()), (0, ZF))
+        }
+
+        #[test]
+        fn simple_abs() {
+            assert_eq!(abs(0xFFFE), (2, Flags::empty()))
+        }
+
+        #[test]
+        fn overflowing_abs() {
+            assert_eq!(abs(0x8000), (0x8000, OF | SF))
+        }
+
+        #[test]
+        fn simple_negate() {
+            assert_eq!(negate(5), (-5i16 as u16, SF))
+        }
+
+        #[test]
+        fn zero_negate() {
+            assert_eq!(negate(0), (0, ZF))
         }
 
         #[test]
         fn dispatch() {
             // 1 + 1 = 2
-            assert_eq!(alu(1, 1, 1, 0), (2, 0))
+            assert_eq!(alu(1, 1, 1, Flags::empty()), (2, Flags::empty()))
+        }
+
+        #[test]
+        fn flags_debug() {
+            let mut flags = Flags::from_bits(ZF.bits() | CF.bits()).unwrap();
+            assert_eq!(format!("{:?}", flags), "ZF | CF");
+            flags.remove(ZF);
+            flags.insert(OF);
+            assert!(flags.contains(CF | OF));
+            assert!(!flags.contains(ZF));
+            assert_eq!(u16::from(flags), 0b0110);
+            assert_eq!(format!("{:?}", Flags::empty()), "(empty)");
+            assert_eq!(format!("{:?}", Flags::from_bits_retain(0x8001)), "ZF | 0x8000");
+            assert_eq!(Flags::from_bits(0x8000), None);
+        }
+
+        /// Check `prop` against edge values and pseudo-random inputs. On a
+        /// failure, shrink each input towards zero while it still fails and
+        /// panic with the smallest case found.
+        fn check(name: &str, prop: impl Fn([u16; 4]) -> bool) {
+            const EDGES: [u16; 6] = [0, 1, 0x7FFF, 0x8000, 0x8001, 0xFFFF];
+            let mut state = 0x2545_F491_4F6C_DD1Du64;
+            let mut random = move || {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state
+            };
+            for i in 0..5000 {
+                let mut input = [0; 4];
+                for (j, n) in input.iter_mut().enumerate() {
+                    let r = random();
+                    // Start with every pair of edge values for a and b.
+                    *n = if i < EDGES.len() * EDGES.len() && j < 2 {
+                        EDGES[if j == 0 { i % EDGES.len() } else { i / EDGES.len() }]
+                    } else if r % 4 == 0 {
+                        EDGES[(r >> 8) as usize % EDGES.len()]
+                    } else {
+                        (r >> 16) as u16
+                    };
+                }
+                if prop(input) {
+                    continue;
+                }
+                let mut shrunk = true;
+                while shrunk {
+                    shrunk = false;
+                    for j in 0..input.len() {
+                        let n = input[j];
+                        for candidate in [0, n / 2, n & 0x7FFF, n.saturating_sub(1)] {
+                            let mut smaller = input;
+                            smaller[j] = candidate;
+                            if candidate < n && !prop(smaller) {
+                                input = smaller;
+                                shrunk = true;
+                                break;
+                            }
+                        }
+                    }
+                }
+                panic!("property {} fails for {:04x?}", name, input);
+            }
+        }
+
+        fn carry(n: u16) -> Flags {
+            if n & 1 == 1 { CF } else { Flags::empty() }
+        }
+
+        #[test]
+        fn add_commutes() {
+            check("add commutes", |[a, b, ..]| add(a, b, Flags::empty()) == add(b, a, Flags::empty()));
+        }
+
+        #[test]
+        fn sub_is_add_negated() {
+            check("sub is add of the negation", |[a, b, ..]| {
+                sub(a, b, Flags::empty()).0 == add(a, b.wrapping_neg(), Flags::empty()).0
+            });
+        }
+
+        #[test]
+        fn cmp_flags_match_sub() {
+            check("cmp flags match sub", |[a, b, f, _]| {
+                let flags = Flags::from_bits_truncate(f);
+                cmp(a, b, flags) == (a, sub(a, b, flags).1)
+            });
+        }
+
+        #[test]
+        fn overflow_is_signed_overflow() {
+            check("add and sub overflow", |[a, b, ..]| {
+                let add_of = (a as i16).checked_add(b as i16).is_none();
+                let sub_of = (a as i16).checked_sub(b as i16).is_none();
+                add(a, b, Flags::empty()).1.contains(OF) == add_of &&
+                    sub(a, b, Flags::empty()).1.contains(OF) == sub_of
+            });
+        }
+
+        #[test]
+        fn adc_chains() {
+            check("add, adc chain to 32 bits", |[a_lo, a_hi, b_lo, b_hi]| {
+                let (lo, flags) = add(a_lo, b_lo, Flags::empty());
+                let (hi, flags) = adc(a_hi, b_hi, flags);
+                let a = (a_hi as u32) << 16 | a_lo as u32;
+                let b = (b_hi as u32) << 16 | b_lo as u32;
+                let (sum, carry) = a.overflowing_add(b);
+                let (_, of) = (a as i32).overflowing_add(b as i32);
+                (hi as u32) << 16 | lo as u32 == sum &&
+                    flags.contains(CF) == carry && flags.contains(OF) == of
+            });
+        }
+
+        #[test]
+        fn sbb_chains() {
+            check("sub, sbb chain to 32 bits", |[a_lo, a_hi, b_lo, b_hi]| {
+                let (lo, flags) = sub(a_lo, b_lo, Flags::empty());
+                let (hi, flags) = sbb(a_hi, b_hi, flags);
+                let a = (a_hi as u32) << 16 | a_lo as u32;
+                let b = (b_hi as u32) << 16 | b_lo as u32;
+                let (difference, borrow) = a.overflowing_sub(b);
+                let (_, of) = (a as i32).overflowing_sub(b as i32);
+                (hi as u32) << 16 | lo as u32 == difference &&
+                    flags.contains(CF) == borrow && flags.contains(OF) == of
+            });
+        }
+
+        #[test]
+        fn adc_sbb_carry_in() {
+            check("adc and sbb take the carry in", |[a, b, c, _]| {
+                let wide = a as u32 + b as u32 + (c & 1) as u32;
+                let narrow = (a as u32).wrapping_sub(b as u32).wrapping_sub((c & 1) as u32);
+                adc(a, b, carry(c)).0 == wide as u16 &&
+                    adc(a, b, carry(c)).1.contains(CF) == (wide > 0xFFFF) &&
+                    sbb(a, b, carry(c)).0 == narrow as u16 &&
+                    sbb(a, b, carry(c)).1.contains(CF) == (narrow > 0xFFFF)
+            });
+        }
+
+        #[test]
+        #[should_panic(expected = "fails for [0000, 0000, 0000, 0000]")]
+        fn check_shrinks() {
+            check("never", |_| false);
         }
     }
 }